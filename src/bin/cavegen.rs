@@ -0,0 +1,219 @@
+//! Generates caves without opening the game window, for looking at many
+//! of them at once.
+//!
+//! ```sh
+//! $ cargo run --bin cavegen -- --seed 1 --count 100 --format png --out caves
+//! ```
+
+extern crate rogue_rs;
+extern crate tcod;
+
+#[macro_use]
+extern crate serde_json;
+
+use std::fs::{self, File};
+use std::path::Path;
+
+use tcod::colors::{self, Color};
+use tcod::image::Image;
+
+use rogue_rs::mapgen::{self, CaveGenerator, CaveRules, MapInfo, Thresholds};
+
+const USAGE: &str = "usage: cavegen [--width 80] [--height 50] [--generations 3] [--fill 40]
+               [--seed 0] [--count 1] [--rules FILE]
+               [--format txt|json|png] [--scale 4] [--out .] [--stats]
+               [--min-open 0.0] [--min-path 1] [--max-dead-ends N] [--attempts 1]
+
+Writes one file per cave, named cave-<seed>.<format>. With --count the
+seeds --seed, --seed + 1, ... are generated. The cave rules are read
+from the --rules JSON file, without it the built-in defaults are used.
+
+Caves that are not connected, or miss the --min-open ratio, --min-path
+length from start to exit or --max-dead-ends, are generated again up to
+--attempts times. Seeds without a good cave are reported and skipped.
+--stats prints the numbers of every written cave.";
+
+fn arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1).cloned())
+}
+
+/// Reports a bad command line with the usage and exits.
+fn usage_error(message: &str) -> ! {
+    eprintln!("cavegen: {}\n\n{}", message, USAGE);
+    std::process::exit(2);
+}
+
+/// Reports an error that is not the command line's fault and exits.
+fn fail(message: &str) -> ! {
+    eprintln!("cavegen: {}", message);
+    std::process::exit(1);
+}
+
+fn arg_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match arg_value(name) {
+        Some(value) => value
+            .parse()
+            .unwrap_or_else(|_| usage_error(&format!("{} takes a number, got '{}'", name, value))),
+        None => default,
+    }
+}
+
+/// Walls as one string per row and colors as one `#rrggbb` per cell.
+fn to_json(cave: &MapInfo, seed: u64) -> serde_json::Value {
+    let rows: Vec<String> = cave.walls.to_string().lines().map(String::from).collect();
+    let colors: Vec<Vec<String>> = cave
+        .colors
+        .rows()
+        .map(|row| {
+            row.iter()
+                .map(|c| format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b))
+                .collect()
+        })
+        .collect();
+    json!({
+        "seed": seed,
+        "width": cave.walls.width,
+        "height": cave.walls.height,
+        "walls": rows,
+        "colors": colors,
+        "start": cave.start,
+        "end": cave.end,
+    })
+}
+
+/// Every cell becomes a `scale` x `scale` square: rock black, floor in its
+/// region color, start green and exit red.
+fn to_image(cave: &MapInfo, scale: usize) -> Image {
+    let (width, height) = (cave.walls.width, cave.walls.height);
+    let mut image = Image::new((width * scale) as i32, (height * scale) as i32);
+
+    for y in 0..height {
+        for x in 0..width {
+            let color: Color = if (x, y) == cave.start {
+                colors::GREEN
+            } else if (x, y) == cave.end {
+                colors::RED
+            } else if cave.walls.get(&(x, y)) == '#' {
+                colors::BLACK
+            } else {
+                cave.colors.get(&(x, y))
+            };
+
+            for j in 0..scale {
+                for i in 0..scale {
+                    image.put_pixel((x * scale + i) as i32, (y * scale + j) as i32, color);
+                }
+            }
+        }
+    }
+    image
+}
+
+fn main() {
+    if std::env::args().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let width = arg_number("--width", 80);
+    let height = arg_number("--height", 50);
+    let generations = arg_number("--generations", 3);
+    let fill = arg_number("--fill", 40);
+    let seed = arg_number("--seed", 0u64);
+    let count = arg_number("--count", 1u64);
+    let scale = arg_number("--scale", 4).max(1);
+    let format = arg_value("--format").unwrap_or_else(|| "txt".to_string());
+    let out = arg_value("--out").unwrap_or_else(|| ".".to_string());
+    let print_stats = std::env::args().any(|arg| arg == "--stats");
+    let attempts = arg_number("--attempts", 1).max(1);
+    let thresholds = Thresholds {
+        min_open_ratio: arg_number("--min-open", 0.0),
+        min_path_length: arg_number("--min-path", 1),
+        max_dead_ends: arg_number("--max-dead-ends", usize::MAX),
+        ..Thresholds::default()
+    };
+
+    if format != "txt" && format != "json" && format != "png" {
+        usage_error(&format!("unknown format '{}', expected txt, json or png", format));
+    }
+    if width < 3 || height < 3 {
+        usage_error("caves need to be at least 3x3");
+    }
+    let end = seed
+        .checked_add(count)
+        .unwrap_or_else(|| usage_error("--seed plus --count is past the largest seed"));
+
+    let rules = match arg_value("--rules") {
+        Some(path) => mapgen::load_rules(Path::new(&path)).unwrap_or_else(|e| usage_error(&e)),
+        None => CaveRules::default(),
+    };
+
+    let generator = CaveGenerator {
+        generations,
+        fill_percentage: fill,
+        rules,
+        thresholds: Some(thresholds),
+        ..CaveGenerator::default()
+    };
+
+    let out = Path::new(&out);
+    fs::create_dir_all(out).unwrap_or_else(|e| fail(&format!("{}: {}", out.display(), e)));
+
+    for seed in seed..end {
+        let mut rng = mapgen::seeded_rng(seed);
+        let cave = match generator.generate_until(width, height, attempts, &mut rng) {
+            Ok(cave) => cave,
+            Err(e) => {
+                eprintln!("seed {}: {}", seed, e);
+                continue;
+            }
+        };
+        let path = out.join(format!("cave-{}.{}", seed, format));
+
+        match format.as_str() {
+            "txt" => fs::write(&path, cave.walls.to_string()),
+            "json" => File::create(&path).and_then(|file| {
+                serde_json::to_writer(file, &to_json(&cave, seed)).map_err(From::from)
+            }),
+            _ => {
+                to_image(&cave, scale).save(&path);
+                Ok(())
+            }
+        }.unwrap_or_else(|e| fail(&format!("{}: {}", path.display(), e)));
+
+        if print_stats {
+            let stats = mapgen::map_stats(&cave);
+            println!(
+                "{}: open {:.2}, regions {} -> {}, path {}, dead ends {}",
+                path.display(),
+                stats.open_ratio,
+                stats.regions_before,
+                stats.regions_after,
+                stats.path_length.unwrap_or(0),
+                stats.dead_ends
+            );
+        } else {
+            println!("{}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_matches_ascii() {
+        let cave = mapgen::generate_cave(30, 20, 3, 40, &CaveRules::default(), 4);
+        let json = to_json(&cave, 4);
+
+        assert_eq!(json["walls"].as_array().unwrap().len(), 20);
+        assert_eq!(json["walls"][0].as_str().unwrap(), "#".repeat(30));
+        assert_eq!(json["colors"][0][0], "#ffffff");
+        assert_eq!(json["start"][0], cave.start.0);
+        assert_eq!(cave.walls.to_string().lines().count(), 20);
+    }
+}
@@ -0,0 +1,304 @@
+use recs::{Ecs, EntityId};
+
+use rand::XorShiftRng;
+
+use serde_json;
+
+use grid::BitGrid;
+use mapgen::{self, MapGenerator};
+use tilemap::TileMap;
+
+/// What the dungeon needs from the game to fill levels with entities and
+/// carry them between levels. The components themselves stay with the
+/// game.
+pub trait Entities {
+    /// Size of generated levels and of the player's memory of a level.
+    fn map_size(&self) -> (usize, usize);
+
+    /// Spawns what `cave` asks for, plus the stairs of a level at `depth`.
+    fn spawn_level(&self, world: &mut Ecs, cave: &mapgen::MapInfo, depth: usize);
+
+    /// Spawns the extras of a generated level, after `spawn_level`.
+    fn spawn_generated(&self, world: &mut Ecs, cave: &mapgen::MapInfo, rng: &mut XorShiftRng);
+
+    fn player(&self, world: &Ecs) -> EntityId;
+
+    /// Every component of `id`, for storing it while its level is off-screen.
+    fn store(&self, world: &Ecs, id: EntityId) -> Vec<serde_json::Value>;
+
+    fn restore(&self, world: &mut Ecs, components: &[serde_json::Value]);
+
+    fn player_position(&self, world: &Ecs) -> (usize, usize);
+
+    fn player_memory(&self, world: &Ecs) -> BitGrid;
+
+    /// Puts the player on `p` of the new current level, remembering what
+    /// `memory` holds of it.
+    fn place_player(&self, world: &mut Ecs, p: (usize, usize), memory: BitGrid);
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Level {
+    pub map: TileMap,
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    /// Every entity except the player, stored while the level is off-screen.
+    pub entities: Vec<Vec<serde_json::Value>>,
+    /// The player's `SpatialMemory` of this level.
+    pub memory: BitGrid,
+}
+
+/// The stack of levels visited so far. Only the level at `depth` has its
+/// entities in the `Ecs`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Dungeon {
+    pub seed: u64,
+    /// Name of the generator the levels come from. The generator itself is
+    /// resolved once by the game and passed in where levels are made.
+    pub generator: String,
+    pub depth: usize,
+    pub levels: Vec<Level>,
+}
+
+impl Dungeon {
+    pub fn new(seed: u64, generator: &str) -> Dungeon {
+        Dungeon {
+            seed,
+            generator: generator.to_string(),
+            depth: 0,
+            levels: vec![],
+        }
+    }
+
+    pub fn current(&self) -> &Level {
+        &self.levels[self.depth]
+    }
+
+    pub fn map(&self) -> &TileMap {
+        &self.current().map
+    }
+
+    /// Each depth gets its own generator stream, so a level looks the same
+    /// no matter in which order the levels were visited.
+    pub fn level_rng(&self, depth: usize) -> XorShiftRng {
+        mapgen::seeded_rng(self.seed ^ (depth as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+    }
+}
+
+/// Generates the next unvisited depth with `generator`, spawns its
+/// entities into `world` and pushes it on the level stack.
+pub fn generate_level<E: Entities>(
+    world: &mut Ecs,
+    dungeon: &mut Dungeon,
+    generator: &dyn MapGenerator,
+    entities: &E,
+) {
+    let depth = dungeon.levels.len();
+    let mut rng = dungeon.level_rng(depth);
+    let (width, height) = entities.map_size();
+    let cave = generator.generate(width, height, &mut rng);
+    add_level(world, dungeon, entities, &cave);
+    entities.spawn_generated(world, &cave, &mut rng);
+}
+
+/// Spawns the entities of an already built map into `world` and pushes it
+/// on the level stack, e.g. a hand-made level from `mapgen::load_level`.
+pub fn add_level<E: Entities>(
+    world: &mut Ecs,
+    dungeon: &mut Dungeon,
+    entities: &E,
+    cave: &mapgen::MapInfo,
+) {
+    let depth = dungeon.levels.len();
+    entities.spawn_level(world, cave, depth);
+    let (width, height) = entities.map_size();
+
+    dungeon.levels.push(Level {
+        map: TileMap::from_map_info(cave),
+        start: cave.start,
+        end: cave.end,
+        entities: vec![],
+        memory: BitGrid::new(width, height, false),
+    });
+}
+
+/// Stores the current level away and makes `depth` the current one. The
+/// player arrives on the start of a deeper level or the exit of a
+/// shallower one.
+pub fn change_level<E: Entities>(
+    world: &mut Ecs,
+    dungeon: &mut Dungeon,
+    generator: &dyn MapGenerator,
+    entities: &E,
+    depth: usize,
+) {
+    let player = entities.player(world);
+    let descending = depth > dungeon.depth;
+
+    let mut ids = vec![];
+    world.collect(&mut ids);
+    ids.retain(|id| *id != player);
+
+    let stored = ids.iter().map(|id| entities.store(world, *id)).collect();
+    for id in ids.iter() {
+        let _ = world.destroy_entity(*id);
+    }
+
+    {
+        let level = &mut dungeon.levels[dungeon.depth];
+        level.entities = stored;
+        level.memory = entities.player_memory(world);
+    }
+
+    if depth == dungeon.levels.len() {
+        generate_level(world, dungeon, generator, entities);
+    } else {
+        let level = &mut dungeon.levels[depth];
+        for components in level.entities.drain(..) {
+            entities.restore(world, &components);
+        }
+    }
+    dungeon.depth = depth;
+
+    let level = dungeon.current();
+    let p = if descending { level.start } else { level.end };
+    entities.place_player(world, p, level.memory.clone());
+}
+
+pub fn descend<E: Entities>(
+    world: &mut Ecs,
+    dungeon: &mut Dungeon,
+    generator: &dyn MapGenerator,
+    entities: &E,
+) {
+    if entities.player_position(world) == dungeon.current().end {
+        let depth = dungeon.depth + 1;
+        change_level(world, dungeon, generator, entities, depth);
+        println!("You descend to depth {}.", depth);
+    }
+}
+
+pub fn ascend<E: Entities>(
+    world: &mut Ecs,
+    dungeon: &mut Dungeon,
+    generator: &dyn MapGenerator,
+    entities: &E,
+) {
+    if dungeon.depth > 0 && entities.player_position(world) == dungeon.current().start {
+        let depth = dungeon.depth - 1;
+        change_level(world, dungeon, generator, entities, depth);
+        println!("You climb up to depth {}.", depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use {entity_to_json, get_player, spawn_player, GameEntities};
+    use {Position, SpatialMemory, MAP_HEIGHT, MAP_WIDTH};
+
+    fn player_at(world: &Ecs, p: (usize, usize)) -> bool {
+        GameEntities.player_position(world) == p
+    }
+
+    fn snapshot(world: &Ecs) -> Vec<String> {
+        let player = get_player(world);
+        let mut entities: Vec<String> = world
+            .iter()
+            .filter(|id| *id != player)
+            .map(|id| serde_json::to_string(&entity_to_json(world, id)).unwrap())
+            .collect();
+        entities.sort();
+        entities
+    }
+
+    fn generator() -> Box<dyn MapGenerator> {
+        mapgen::generator_by_name("cave").unwrap()
+    }
+
+    fn new_game(seed: u64) -> (Ecs, Dungeon) {
+        let mut world = Ecs::new();
+        let mut dungeon = Dungeon::new(seed, "cave");
+        generate_level(&mut world, &mut dungeon, &*generator(), &GameEntities);
+
+        let (x, y) = dungeon.current().start;
+        spawn_player(&mut world, x as i32, y as i32);
+        (world, dungeon)
+    }
+
+    #[test]
+    fn test_descend_and_return() {
+        let (mut world, mut dungeon) = new_game(5);
+        let player = get_player(&world);
+        let before = snapshot(&world);
+
+        let mut memory = BitGrid::new(MAP_WIDTH as usize, MAP_HEIGHT as usize, false);
+        memory.set(&(3, 0), true);
+        let _ = world.set(player, SpatialMemory::new(memory.clone()));
+
+        change_level(&mut world, &mut dungeon, &*generator(), &GameEntities, 1);
+        assert_eq!(dungeon.depth, 1);
+        assert_eq!(dungeon.levels.len(), 2);
+        assert!(snapshot(&world) != before);
+
+        let start = dungeon.current().start;
+        assert!(player_at(&world, start));
+        assert!(!world.get::<SpatialMemory>(player).unwrap().memory.get(&(3, 0)));
+
+        change_level(&mut world, &mut dungeon, &*generator(), &GameEntities, 0);
+        assert_eq!(dungeon.depth, 0);
+        assert_eq!(snapshot(&world), before);
+        assert_eq!(world.get::<SpatialMemory>(player).unwrap().memory, memory);
+
+        let end = dungeon.current().end;
+        assert!(player_at(&world, end));
+    }
+
+    #[test]
+    fn test_descend_needs_exit() {
+        let (mut world, mut dungeon) = new_game(6);
+
+        descend(&mut world, &mut dungeon, &*generator(), &GameEntities);
+        assert_eq!(dungeon.depth, 0);
+
+        let player = get_player(&world);
+        let (x, y) = dungeon.current().end;
+        let _ = world.set(player, Position::new(x as i32, y as i32));
+
+        descend(&mut world, &mut dungeon, &*generator(), &GameEntities);
+        assert_eq!(dungeon.depth, 1);
+
+        ascend(&mut world, &mut dungeon, &*generator(), &GameEntities);
+        assert_eq!(dungeon.depth, 0);
+    }
+
+    #[test]
+    fn test_hand_made_first_level() {
+        let cave = mapgen::parse_level("room", "---\n#####\n#@.>#\n#####\n").unwrap();
+        let mut world = Ecs::new();
+        let mut dungeon = Dungeon::new(3, "cave");
+        add_level(&mut world, &mut dungeon, &GameEntities, &cave);
+        spawn_player(&mut world, 1, 1);
+
+        assert_eq!(dungeon.current().end, (3, 1));
+        let _ = world.set(get_player(&world), Position::new(3, 1));
+        descend(&mut world, &mut dungeon, &*generator(), &GameEntities);
+
+        assert_eq!(dungeon.depth, 1);
+        assert_eq!(dungeon.levels[1].map.width(), MAP_WIDTH);
+    }
+
+    #[test]
+    fn test_levels_are_reproducible() {
+        let (mut world, mut dungeon) = new_game(7);
+        change_level(&mut world, &mut dungeon, &*generator(), &GameEntities, 1);
+
+        let mut other_world = Ecs::new();
+        let mut other = Dungeon::new(7, "cave");
+        generate_level(&mut other_world, &mut other, &*generator(), &GameEntities);
+        generate_level(&mut other_world, &mut other, &*generator(), &GameEntities);
+
+        assert_eq!(dungeon.levels[1].map, other.levels[1].map);
+    }
+}
@@ -0,0 +1,305 @@
+use serde::de::{Deserialize, Deserializer, Error};
+use serde::ser::{Serialize, Serializer};
+
+const BITS: usize = 64;
+
+/// A grid of booleans packed 64 to a word, for field of view, memory and
+/// other yes/no maps that are merged and saved often.
+///
+/// Serialized as run lengths: the number of `false` cells, then `true`
+/// cells, then `false` again and so on, row by row.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BitGrid {
+    pub width: usize,
+    pub height: usize,
+    words: Vec<u64>,
+}
+
+/// The serialized form of a `BitGrid`.
+#[derive(Serialize, Deserialize)]
+struct Runs {
+    width: usize,
+    height: usize,
+    runs: Vec<usize>,
+}
+
+impl BitGrid {
+    pub fn new(width: usize, height: usize, init: bool) -> BitGrid {
+        let word = if init { !0 } else { 0 };
+        let mut grid = BitGrid {
+            width,
+            height,
+            words: vec![word; (width * height).div_ceil(BITS)],
+        };
+        grid.clear_padding();
+        grid
+    }
+
+    fn len(&self) -> usize {
+        self.width * self.height
+    }
+
+    // Bits past the last cell stay zero, so whole words can be compared
+    // and counted.
+    fn clear_padding(&mut self) {
+        let used = self.len() % BITS;
+        if used != 0 {
+            if let Some(last) = self.words.last_mut() {
+                *last &= (1 << used) - 1;
+            }
+        }
+    }
+
+    fn index(&self, p: &(usize, usize)) -> usize {
+        assert!(self.in_bounds(p), "{:?} is outside the grid", p);
+        p.1 * self.width + p.0
+    }
+
+    pub fn in_bounds(&self, p: &(usize, usize)) -> bool {
+        p.0 < self.width && p.1 < self.height
+    }
+
+    pub fn get(&self, p: &(usize, usize)) -> bool {
+        let i = self.index(p);
+        self.words[i / BITS] & (1 << (i % BITS)) != 0
+    }
+
+    pub fn set(&mut self, p: &(usize, usize), value: bool) {
+        let i = self.index(p);
+        if value {
+            self.words[i / BITS] |= 1 << (i % BITS);
+        } else {
+            self.words[i / BITS] &= !(1 << (i % BITS));
+        }
+    }
+
+    pub fn try_get(&self, p: &(usize, usize)) -> Option<bool> {
+        if self.in_bounds(p) {
+            Some(self.get(p))
+        } else {
+            None
+        }
+    }
+
+    pub fn try_get_signed(&self, p: &(i32, i32)) -> Option<bool> {
+        if p.0 < 0 || p.1 < 0 {
+            None
+        } else {
+            self.try_get(&(p.0 as usize, p.1 as usize))
+        }
+    }
+
+    /// Sets every cell to `value`.
+    pub fn fill(&mut self, value: bool) {
+        *self = BitGrid::new(self.width, self.height, value);
+    }
+
+    /// Number of `true` cells.
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Coordinates of the `true` cells, row by row.
+    pub fn ones<'a>(&'a self) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let width = self.width;
+        (0..self.len())
+            .filter(move |&i| self.words[i / BITS] & (1 << (i % BITS)) != 0)
+            .map(move |i| (i % width, i / width))
+    }
+
+    fn combine<F: Fn(u64, u64) -> u64>(&mut self, other: &BitGrid, f: F) {
+        assert_eq!(
+            (self.width, self.height),
+            (other.width, other.height),
+            "combined grids differ in size"
+        );
+        for (a, b) in self.words.iter_mut().zip(other.words.iter()) {
+            *a = f(*a, *b);
+        }
+    }
+
+    /// Sets every cell that is set in `other`.
+    pub fn union_with(&mut self, other: &BitGrid) {
+        self.combine(other, |a, b| a | b);
+    }
+
+    /// Clears every cell that is not set in `other`.
+    pub fn intersect_with(&mut self, other: &BitGrid) {
+        self.combine(other, |a, b| a & b);
+    }
+
+    /// Clears every cell that is set in `other`.
+    pub fn difference_with(&mut self, other: &BitGrid) {
+        self.combine(other, |a, b| a & !b);
+    }
+
+    pub fn union(&self, other: &BitGrid) -> BitGrid {
+        let mut grid = self.clone();
+        grid.union_with(other);
+        grid
+    }
+
+    pub fn intersection(&self, other: &BitGrid) -> BitGrid {
+        let mut grid = self.clone();
+        grid.intersect_with(other);
+        grid
+    }
+
+    pub fn difference(&self, other: &BitGrid) -> BitGrid {
+        let mut grid = self.clone();
+        grid.difference_with(other);
+        grid
+    }
+
+    fn runs(&self) -> Vec<usize> {
+        let mut runs = vec![];
+        let mut current = false;
+        let mut length = 0;
+        for i in 0..self.len() {
+            let bit = self.words[i / BITS] & (1 << (i % BITS)) != 0;
+            if bit != current {
+                runs.push(length);
+                current = bit;
+                length = 0;
+            }
+            length += 1;
+        }
+        runs.push(length);
+        runs
+    }
+
+    fn from_runs(width: usize, height: usize, runs: &[usize]) -> Result<BitGrid, String> {
+        let cells = width
+            .checked_mul(height)
+            .ok_or_else(|| format!("a {}x{} grid is too large", width, height))?;
+        let total = runs
+            .iter()
+            .try_fold(0usize, |total, &length| total.checked_add(length))
+            .ok_or_else(|| "runs cover more cells than fit in a usize".to_string())?;
+        if total != cells {
+            return Err(format!(
+                "runs cover {} cells, a {}x{} grid has {}",
+                total, width, height, cells
+            ));
+        }
+
+        let mut grid = BitGrid::new(width, height, false);
+        let mut i = 0;
+        for (n, &length) in runs.iter().enumerate() {
+            if n % 2 == 1 {
+                for j in i..i + length {
+                    grid.words[j / BITS] |= 1 << (j % BITS);
+                }
+            }
+            i += length;
+        }
+        Ok(grid)
+    }
+}
+
+impl Serialize for BitGrid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Runs {
+            width: self.width,
+            height: self.height,
+            runs: self.runs(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BitGrid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BitGrid, D::Error> {
+        let runs = Runs::deserialize(deserializer)?;
+        BitGrid::from_runs(runs.width, runs.height, &runs.runs).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    fn diagonal(size: usize) -> BitGrid {
+        let mut grid = BitGrid::new(size, size, false);
+        for i in 0..size {
+            grid.set(&(i, i), true);
+        }
+        grid
+    }
+
+    #[test]
+    fn test_get_set() {
+        let mut grid = BitGrid::new(70, 3, false);
+        grid.set(&(65, 0), true);
+        grid.set(&(0, 1), true);
+        grid.set(&(69, 2), true);
+
+        assert!(grid.get(&(65, 0)));
+        assert!(grid.get(&(0, 1)));
+        assert!(!grid.get(&(1, 1)));
+        assert_eq!(grid.count_ones(), 3);
+        assert_eq!(grid.ones().collect::<Vec<_>>(), vec![(65, 0), (0, 1), (69, 2)]);
+
+        grid.set(&(65, 0), false);
+        assert_eq!(grid.count_ones(), 2);
+        assert_eq!(grid.try_get(&(70, 0)), None);
+        assert_eq!(grid.try_get_signed(&(-1, 0)), None);
+        assert_eq!(grid.try_get_signed(&(69, 2)), Some(true));
+    }
+
+    #[test]
+    fn test_new_filled() {
+        let grid = BitGrid::new(7, 11, true);
+
+        assert_eq!(grid.count_ones(), 77);
+        assert!(grid.get(&(6, 10)));
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a = diagonal(10);
+        let mut b = BitGrid::new(10, 10, false);
+        b.set(&(0, 0), true);
+        b.set(&(1, 0), true);
+
+        assert_eq!(a.union(&b).count_ones(), 11);
+        assert_eq!(a.intersection(&b).ones().collect::<Vec<_>>(), vec![(0, 0)]);
+        assert_eq!(a.difference(&b).count_ones(), 9);
+        assert_eq!(b.difference(&a).ones().collect::<Vec<_>>(), vec![(1, 0)]);
+
+        let mut c = a.clone();
+        c.union_with(&b);
+        c.intersect_with(&b);
+        assert_eq!(c, b);
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let mut grid = diagonal(80);
+        grid.set(&(5, 0), true);
+        grid.set(&(6, 0), true);
+        let json = serde_json::to_string(&grid).unwrap();
+
+        assert_eq!(serde_json::from_str::<BitGrid>(&json).unwrap(), grid);
+        // Far smaller than one `true`/`false` per cell.
+        assert!(json.len() < 80 * 80 / 10);
+
+        let full = BitGrid::new(4, 4, true);
+        let json = serde_json::to_string(&full).unwrap();
+        assert_eq!(json, r#"{"width":4,"height":4,"runs":[0,16]}"#);
+        assert_eq!(serde_json::from_str::<BitGrid>(&json).unwrap(), full);
+    }
+
+    #[test]
+    fn test_bad_runs() {
+        let json = r#"{"width":4,"height":4,"runs":[3,4]}"#;
+
+        assert!(serde_json::from_str::<BitGrid>(json).is_err());
+
+        let overflow = format!(r#"{{"width":1,"height":2,"runs":[{},2]}}"#, usize::MAX);
+        assert!(serde_json::from_str::<BitGrid>(&overflow).is_err());
+        let huge = format!(r#"{{"width":{},"height":2,"runs":[]}}"#, usize::MAX);
+        assert!(serde_json::from_str::<BitGrid>(&huge).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use grid::{BitGrid, NodeMap};
+
+/// Value of the cells none of the goals can be reached from.
+pub const UNREACHED: i32 = i32::MAX;
+
+/// Distances to the nearest of a set of goal cells. Whoever stands on the
+/// map walks towards the goals by stepping to the lowest neighbour, see
+/// `downhill`.
+///
+/// `costs` holds what it takes to step onto each cell, `None` for cells
+/// that cannot be entered. Costs must not be negative. Moving the goals or
+/// changing a cost only recomputes the cells that depended on them, so
+/// the map can follow a moving player every turn.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DijkstraMap {
+    costs: NodeMap<Option<i32>>,
+    /// The value each source cell starts out with, 0 for goals.
+    sources: NodeMap<Option<i32>>,
+    values: NodeMap<i32>,
+}
+
+impl DijkstraMap {
+    pub fn new(costs: NodeMap<Option<i32>>, goals: &[(usize, usize)]) -> DijkstraMap {
+        let (width, height) = (costs.width, costs.height);
+        let mut map = DijkstraMap {
+            costs,
+            sources: NodeMap::new(width, height, None),
+            values: NodeMap::new(width, height, UNREACHED),
+        };
+        map.set_goals(goals);
+        map
+    }
+
+    /// Distance of every cell to the nearest goal, `UNREACHED` where there
+    /// is no way to one.
+    pub fn values(&self) -> &NodeMap<i32> {
+        &self.values
+    }
+
+    /// The distance at `p`, `None` if no goal can be reached from it or
+    /// `p` is outside the map.
+    pub fn get(&self, p: &(usize, usize)) -> Option<i32> {
+        self.values.try_get(p).filter(|&v| v != UNREACHED)
+    }
+
+    pub fn cost(&self, p: &(usize, usize)) -> Option<i32> {
+        self.costs.try_get(p).and_then(|c| c)
+    }
+
+    /// Replaces the goals. Goals outside the map are ignored.
+    pub fn set_goals(&mut self, goals: &[(usize, usize)]) {
+        let mut sources = NodeMap::new(self.values.width, self.values.height, None);
+        for goal in goals.iter() {
+            sources.try_set(goal, Some(0));
+        }
+        self.set_sources(sources);
+    }
+
+    /// Changes what it takes to step onto `p`, for a door that opened or a
+    /// cell that filled with rubble.
+    pub fn set_cost(&mut self, p: &(usize, usize), cost: Option<i32>) {
+        if self.costs.try_set(p, cost) {
+            self.repair(vec![*p]);
+        }
+    }
+
+    /// Replaces every cost at once, recomputing only around the cells
+    /// whose cost changed. Panics if `costs` is not the size of the map.
+    pub fn set_costs(&mut self, costs: NodeMap<Option<i32>>) {
+        let dirty = self
+            .costs
+            .zip_with(&costs, |old, new| old != new)
+            .enumerate()
+            .filter(|&(_, &dirty)| dirty)
+            .map(|(p, _)| p)
+            .collect();
+        self.costs = costs;
+        self.repair(dirty);
+    }
+
+    /// A map for running away from the goals of this one. The distances
+    /// are turned negative and scaled by `scale`, then smoothed out again,
+    /// so going downhill leads away from the goals. With a `scale` a bit
+    /// above 1, like 1.2, a longer way around past the goals wins over a
+    /// dead end close by.
+    pub fn flee(&self, scale: f32) -> DijkstraMap {
+        let (width, height) = (self.values.width, self.values.height);
+        let sources = self.values.map(|&v| {
+            if v == UNREACHED {
+                None
+            } else {
+                Some((v as f32 * -scale).round() as i32)
+            }
+        });
+        let mut map = DijkstraMap {
+            costs: self.costs.clone(),
+            sources: NodeMap::new(width, height, None),
+            values: NodeMap::new(width, height, UNREACHED),
+        };
+        map.set_sources(sources);
+        map
+    }
+
+    /// The neighbour of `p` with the lowest value, if that is lower than
+    /// the value at `p`. `None` on a goal or when no step gets closer.
+    pub fn downhill(&self, p: &(usize, usize)) -> Option<(usize, usize)> {
+        let here = self.values.try_get(p)?;
+        self.values
+            .neighbours4(p)
+            .filter(|n| self.values.get(n) < here)
+            .min_by_key(|n| self.values.get(n))
+    }
+
+    /// The cells `downhill` leads through from `from`, not including
+    /// `from` itself.
+    pub fn path(&self, from: &(usize, usize)) -> Vec<(usize, usize)> {
+        let mut path = vec![];
+        let mut p = *from;
+        while let Some(next) = self.downhill(&p) {
+            path.push(next);
+            p = next;
+        }
+        path
+    }
+
+    fn set_sources(&mut self, sources: NodeMap<Option<i32>>) {
+        // Sources that went away or got worse take everything that was
+        // built on them along.
+        let dirty = self
+            .sources
+            .zip_with(&sources, |&old, &new| match (old, new) {
+                (Some(old), Some(new)) => new > old,
+                (Some(_), None) => true,
+                (None, _) => false,
+            })
+            .enumerate()
+            .filter(|&(_, &dirty)| dirty)
+            .map(|(p, _)| p)
+            .collect();
+        self.sources = sources;
+        self.repair(dirty);
+    }
+
+    /// Forgets the values of `dirty` and of every cell whose value could
+    /// have come through one of them, then fills them in again from the
+    /// cells around them and from the sources.
+    fn repair(&mut self, dirty: Vec<(usize, usize)>) {
+        let (width, height) = (self.values.width, self.values.height);
+        let mut invalid = BitGrid::new(width, height, false);
+        let mut stack = dirty;
+
+        while let Some(p) = stack.pop() {
+            if invalid.get(&p) {
+                continue;
+            }
+            invalid.set(&p, true);
+            let value = self.values.get(&p);
+            if value == UNREACHED {
+                continue;
+            }
+            for n in self.values.neighbours4(&p) {
+                let through_p = self.costs.get(&n).map(|c| value + c) == Some(self.values.get(&n));
+                let is_source = self.sources.get(&n) == Some(self.values.get(&n));
+                if through_p && !is_source && !invalid.get(&n) {
+                    stack.push(n);
+                }
+            }
+        }
+
+        let invalid: Vec<(usize, usize)> = invalid.ones().collect();
+        for p in invalid.iter() {
+            self.values.set(p, UNREACHED);
+        }
+
+        let mut heap = BinaryHeap::new();
+        for p in invalid.iter() {
+            if let Some(cost) = self.costs.get(p) {
+                let best = self
+                    .values
+                    .neighbours4(p)
+                    .map(|n| self.values.get(&n))
+                    .filter(|&v| v != UNREACHED)
+                    .min();
+                if let Some(best) = best {
+                    self.values.set(p, best + cost);
+                    heap.push((Reverse(best + cost), *p));
+                }
+            }
+        }
+        for (p, &source) in self.sources.enumerate() {
+            if let Some(source) = source {
+                if source < self.values.get(&p) {
+                    self.values.set(&p, source);
+                    heap.push((Reverse(source), p));
+                }
+            }
+        }
+
+        while let Some((Reverse(value), p)) = heap.pop() {
+            if value > self.values.get(&p) {
+                continue;
+            }
+            for n in self.values.neighbours4(&p) {
+                if let Some(cost) = self.costs.get(&n) {
+                    if value + cost < self.values.get(&n) {
+                        self.values.set(&n, value + cost);
+                        heap.push((Reverse(value + cost), n));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::Rng;
+
+    use mapgen::seeded_rng;
+
+    fn costs(text: &str) -> NodeMap<Option<i32>> {
+        let nm: NodeMap<char> = text.parse().unwrap();
+        nm.map(|&c| match c {
+            '#' => None,
+            '~' => Some(3),
+            _ => Some(1),
+        })
+    }
+
+    #[test]
+    fn test_distances() {
+        let map = DijkstraMap::new(costs("#####\n#..~#\n#.#.#\n#####\n"), &[(1, 1)]);
+
+        assert_eq!(map.get(&(1, 1)), Some(0));
+        assert_eq!(map.get(&(2, 1)), Some(1));
+        assert_eq!(map.get(&(3, 1)), Some(4));
+        assert_eq!(map.get(&(3, 2)), Some(5));
+        assert_eq!(map.get(&(0, 0)), None);
+        assert_eq!(map.path(&(3, 2)), vec![(3, 1), (2, 1), (1, 1)]);
+        assert_eq!(map.downhill(&(1, 1)), None);
+    }
+
+    #[test]
+    fn test_several_goals() {
+        let map = DijkstraMap::new(costs(".......\n"), &[(0, 0), (6, 0)]);
+
+        assert_eq!(map.values().iter().cloned().collect::<Vec<_>>(), vec![0, 1, 2, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_moving_goal() {
+        let text = "#######\n#.....#\n#.###.#\n#.....#\n#######\n";
+        let mut map = DijkstraMap::new(costs(text), &[(1, 1)]);
+        map.set_goals(&[(5, 3)]);
+
+        assert_eq!(map, DijkstraMap::new(costs(text), &[(5, 3)]));
+        assert_eq!(map.get(&(1, 1)), Some(6));
+
+        map.set_goals(&[]);
+        assert!(map.values().iter().all(|&v| v == UNREACHED));
+    }
+
+    #[test]
+    fn test_set_cost() {
+        let text = "#######\n#.....#\n#.###.#\n#.....#\n#######\n";
+        let mut map = DijkstraMap::new(costs(text), &[(1, 1)]);
+
+        map.set_cost(&(2, 1), None);
+        assert_eq!(map.get(&(3, 1)), Some(10));
+        assert_eq!(map.get(&(2, 1)), None);
+
+        map.set_cost(&(2, 1), Some(1));
+        assert_eq!(map, DijkstraMap::new(costs(text), &[(1, 1)]));
+
+        let walled = costs("#######\n#.#...#\n#.###.#\n#.....#\n#######\n");
+        map.set_costs(walled.clone());
+        assert_eq!(map, DijkstraMap::new(walled, &[(1, 1)]));
+    }
+
+    #[test]
+    fn test_incremental_matches_fresh() {
+        let mut rng = seeded_rng(5);
+        let cells = (0..30 * 20)
+            .map(|_| if rng.gen::<f32>() < 0.3 { None } else { Some(rng.gen_range(1, 4)) })
+            .collect();
+        let mut costs = NodeMap::from_vec(30, 20, cells);
+        let mut map = DijkstraMap::new(costs.clone(), &[(0, 0)]);
+
+        for _ in 0..50 {
+            let goals: Vec<_> = (0..rng.gen_range(0, 4))
+                .map(|_| (rng.gen_range(0, 30), rng.gen_range(0, 20)))
+                .collect();
+            map.set_goals(&goals);
+            let p = (rng.gen_range(0, 30), rng.gen_range(0, 20));
+            let cost = if rng.gen() { None } else { Some(rng.gen_range(1, 4)) };
+            map.set_cost(&p, cost);
+            costs.set(&p, cost);
+
+            assert_eq!(map, DijkstraMap::new(costs.clone(), &goals));
+        }
+    }
+
+    #[test]
+    fn test_flee() {
+        // Running from the goal at the left leads right, even from the
+        // middle of the corridor.
+        let map = DijkstraMap::new(costs("........\n"), &[(0, 0)]);
+        let flee = map.flee(1.2);
+
+        assert_eq!(flee.downhill(&(3, 0)), Some((4, 0)));
+        assert_eq!(flee.path(&(0, 0)).last(), Some(&(7, 0)));
+        assert!(flee.get(&(7, 0)).unwrap() < 0);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+
+use grid::NodeMap;
+
+/// Cells on the straight line from `from` to `to`, both included, in
+/// walking order.
+pub fn line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let (sx, sy) = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+    let (mut x, mut y) = from;
+    let mut err = dx + dy;
+    let mut cells = vec![from];
+
+    while (x, y) != to {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+        cells.push((x, y));
+    }
+    cells
+}
+
+fn in_circle(dx: i32, dy: i32, radius: i32) -> bool {
+    // The extra `radius` rounds the shape, a bare r * r leaves single
+    // cells sticking out at the four tips.
+    dx * dx + dy * dy <= radius * radius + radius
+}
+
+/// Every cell within `radius` of `center`, row by row.
+pub fn circle(center: (i32, i32), radius: i32) -> Vec<(i32, i32)> {
+    let mut cells = vec![];
+    for dy in -radius..radius + 1 {
+        for dx in -radius..radius + 1 {
+            if in_circle(dx, dy, radius) {
+                cells.push((center.0 + dx, center.1 + dy));
+            }
+        }
+    }
+    cells
+}
+
+/// The cells of `circle` that have an orthogonal neighbour outside it.
+pub fn circle_outline(center: (i32, i32), radius: i32) -> Vec<(i32, i32)> {
+    circle(center, radius)
+        .into_iter()
+        .filter(|&(x, y)| {
+            let (dx, dy) = (x - center.0, y - center.1);
+            !(in_circle(dx - 1, dy, radius)
+                && in_circle(dx + 1, dy, radius)
+                && in_circle(dx, dy - 1, radius)
+                && in_circle(dx, dy + 1, radius))
+        })
+        .collect()
+}
+
+/// Steps when moving diagonally costs the same as moving straight.
+pub fn chebyshev(a: (i32, i32), b: (i32, i32)) -> i32 {
+    (a.0 - b.0).abs().max((a.1 - b.1).abs())
+}
+
+/// Steps when only moving straight.
+pub fn manhattan(a: (i32, i32), b: (i32, i32)) -> i32 {
+    (a.0 - b.0).abs() + (a.1 - b.1).abs()
+}
+
+pub fn euclidean(a: (i32, i32), b: (i32, i32)) -> f32 {
+    let (dx, dy) = ((a.0 - b.0) as f32, (a.1 - b.1) as f32);
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// An axis-aligned rectangle of map cells.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize, new)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+impl Rect {
+    pub fn center(&self) -> (usize, usize) {
+        (self.x + self.w / 2, self.y + self.h / 2)
+    }
+
+    pub fn contains(&self, p: &(usize, usize)) -> bool {
+        p.0 >= self.x && p.1 >= self.y && p.0 < self.x + self.w && p.1 < self.y + self.h
+    }
+
+    /// True if the two share at least one cell.
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.intersection(other).is_some()
+    }
+
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.w).min(other.x + other.w);
+        let bottom = (self.y + self.h).min(other.y + other.h);
+        if x < right && y < bottom {
+            Some(Rect::new(x, y, right - x, bottom - y))
+        } else {
+            None
+        }
+    }
+
+    /// Every cell of the rectangle, row by row.
+    pub fn points(&self) -> impl Iterator<Item = (usize, usize)> {
+        let (x, w) = (self.x, self.w);
+        (self.y..self.y + self.h).flat_map(move |y| (x..x + w).map(move |x| (x, y)))
+    }
+
+    /// The cells within `thickness` of the rectangle's border.
+    pub fn border(&self, thickness: usize) -> impl Iterator<Item = (usize, usize)> {
+        let inner = Rect::new(
+            self.x + thickness,
+            self.y + thickness,
+            self.w.saturating_sub(2 * thickness),
+            self.h.saturating_sub(2 * thickness),
+        );
+        self.points().filter(move |p| !inner.contains(p))
+    }
+}
+
+/// Steps from the nearest of `sources` to every cell, walking orthogonally
+/// through cells `passable` accepts. Cells that cannot be reached are
+/// `None`. Sources count as reached even if they are not passable.
+pub fn distance_field<C: Clone, F: Fn(&C) -> bool>(
+    map: &NodeMap<C>,
+    sources: &[(usize, usize)],
+    passable: F,
+) -> NodeMap<Option<u32>> {
+    let mut field = NodeMap::new(map.width, map.height, None);
+    let mut queue = VecDeque::new();
+
+    for source in sources.iter().filter(|p| map.in_bounds(p)) {
+        if field.get(source).is_none() {
+            field.set(source, Some(0));
+            queue.push_back(*source);
+        }
+    }
+
+    while let Some(p) = queue.pop_front() {
+        let next = field.get(&p).unwrap() + 1;
+        for n in map.neighbours4(&p) {
+            if field.get(&n).is_none() && passable(&map.get(&n)) {
+                field.set(&n, Some(next));
+                queue.push_back(n);
+            }
+        }
+    }
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        assert_eq!(line((0, 0), (3, 0)), vec![(0, 0), (1, 0), (2, 0), (3, 0)]);
+        assert_eq!(line((2, 2), (2, 2)), vec![(2, 2)]);
+        assert_eq!(line((0, 0), (-2, -2)), vec![(0, 0), (-1, -1), (-2, -2)]);
+
+        let steep = line((1, 1), (3, 6));
+        assert_eq!(steep.len(), 6);
+        assert_eq!(steep[0], (1, 1));
+        assert_eq!(steep[5], (3, 6));
+        for pair in steep.windows(2) {
+            assert_eq!(chebyshev(pair[0], pair[1]), 1);
+        }
+    }
+
+    #[test]
+    fn test_circle() {
+        assert_eq!(circle((5, 5), 0), vec![(5, 5)]);
+
+        let disc = circle((0, 0), 3);
+        assert!(disc.contains(&(3, 0)));
+        assert!(disc.contains(&(2, 2)));
+        assert!(!disc.contains(&(3, 3)));
+
+        let outline = circle_outline((0, 0), 3);
+        assert!(outline.contains(&(3, 0)));
+        assert!(!outline.contains(&(0, 0)));
+        assert!(outline.iter().all(|p| disc.contains(p)));
+    }
+
+    #[test]
+    fn test_distances() {
+        assert_eq!(chebyshev((0, 0), (3, -4)), 4);
+        assert_eq!(manhattan((0, 0), (3, -4)), 7);
+        assert_eq!(euclidean((0, 0), (3, -4)), 5.0);
+    }
+
+    #[test]
+    fn test_rect() {
+        let a = Rect::new(0, 0, 4, 3);
+        let b = Rect::new(3, 2, 4, 4);
+        let c = Rect::new(4, 0, 2, 2);
+
+        assert_eq!(a.center(), (2, 1));
+        assert!(a.contains(&(3, 2)));
+        assert!(!a.contains(&(4, 2)));
+        assert_eq!(a.intersection(&b), Some(Rect::new(3, 2, 1, 1)));
+        assert!(!a.intersects(&c));
+        assert_eq!(a.points().count(), 12);
+        assert_eq!(a.border(1).count(), 10);
+        assert_eq!(Rect::new(0, 0, 5, 5).border(2).count(), 24);
+    }
+
+    #[test]
+    fn test_distance_field() {
+        let map: NodeMap<char> = "#####\n#..##\n#.#.#\n#####\n".parse().unwrap();
+        let field = distance_field(&map, &[(1, 1)], |&c| c != '#');
+
+        assert_eq!(field.get(&(1, 1)), Some(0));
+        assert_eq!(field.get(&(2, 1)), Some(1));
+        assert_eq!(field.get(&(1, 2)), Some(1));
+        assert_eq!(field.get(&(3, 2)), None);
+        assert_eq!(field.get(&(0, 0)), None);
+
+        let two = distance_field(&map, &[(1, 2), (3, 2)], |&c| c != '#');
+        assert_eq!(two.get(&(2, 1)), Some(2));
+        assert_eq!(two.get(&(3, 2)), Some(0));
+    }
+}
@@ -1,6 +1,35 @@
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display};
+use std::slice::{Chunks, Iter, IterMut};
+use std::str::FromStr;
 
-#[derive(Clone, Eq, Hash, PartialEq, Debug)]
+mod bitgrid;
+mod dijkstra;
+mod geometry;
+mod view;
+
+pub use self::bitgrid::BitGrid;
+pub use self::dijkstra::{DijkstraMap, UNREACHED};
+pub use self::geometry::{
+    chebyshev, circle, circle_outline, distance_field, euclidean, line, manhattan, Rect,
+};
+pub use self::view::View;
+
+/// Offsets of the four orthogonal neighbours: left, up, right, down.
+pub const OFFSETS4: [(i32, i32); 4] = [(-1, 0), (0, -1), (1, 0), (0, 1)];
+
+/// Offsets of all eight neighbours, row by row.
+pub const OFFSETS8: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+#[derive(Clone, Eq, Hash, PartialEq, Debug, Serialize, Deserialize)]
 pub struct NodeMap<C> {
     pub width: usize,
     pub height: usize,
@@ -20,6 +49,113 @@ impl<C> NodeMap<C> {
             grid: values,
         }
     }
+
+    pub fn in_bounds(&self, p: &(usize, usize)) -> bool {
+        p.0 < self.width && p.1 < self.height
+    }
+
+    pub fn in_bounds_signed(&self, p: &(i32, i32)) -> bool {
+        self.to_unsigned(p).is_some()
+    }
+
+    /// The same point in `usize` coordinates, `None` if it is outside.
+    pub fn to_unsigned(&self, p: &(i32, i32)) -> Option<(usize, usize)> {
+        if p.0 >= 0 && p.1 >= 0 && (p.0 as usize) < self.width && (p.1 as usize) < self.height {
+            Some((p.0 as usize, p.1 as usize))
+        } else {
+            None
+        }
+    }
+
+    /// `p` moved by `offset`, `None` if that leaves the map.
+    pub fn offset(&self, p: &(usize, usize), offset: (i32, i32)) -> Option<(usize, usize)> {
+        self.to_unsigned(&(p.0 as i32 + offset.0, p.1 as i32 + offset.1))
+    }
+
+    /// The orthogonal neighbours of `p` inside the map, see `OFFSETS4`.
+    pub fn neighbours4(&self, p: &(usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+        neighbours(p, &OFFSETS4, self.width, self.height)
+    }
+
+    /// All neighbours of `p` inside the map, see `OFFSETS8`.
+    pub fn neighbours8(&self, p: &(usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+        neighbours(p, &OFFSETS8, self.width, self.height)
+    }
+
+    /// The cells row by row, left to right.
+    pub fn iter<'a>(&'a self) -> Iter<'a, C> {
+        self.grid.iter()
+    }
+
+    pub fn iter_mut<'a>(&'a mut self) -> IterMut<'a, C> {
+        self.grid.iter_mut()
+    }
+
+    /// Like `iter`, with the coordinates of every cell.
+    pub fn enumerate<'a>(&'a self) -> impl Iterator<Item = ((usize, usize), &'a C)> + 'a {
+        let width = self.width;
+        self.grid
+            .iter()
+            .enumerate()
+            .map(move |(i, c)| ((i % width, i / width), c))
+    }
+
+    pub fn enumerate_mut<'a>(
+        &'a mut self,
+    ) -> impl Iterator<Item = ((usize, usize), &'a mut C)> + 'a {
+        let width = self.width;
+        self.grid
+            .iter_mut()
+            .enumerate()
+            .map(move |(i, c)| ((i % width, i / width), c))
+    }
+
+    /// One slice per row, top to bottom.
+    pub fn rows<'a>(&'a self) -> Chunks<'a, C> {
+        self.grid.chunks(self.width.max(1))
+    }
+
+    /// A map of the same size with `f` applied to every cell.
+    pub fn map<D, F: FnMut(&C) -> D>(&self, f: F) -> NodeMap<D> {
+        NodeMap::from_vec(self.width, self.height, self.grid.iter().map(f).collect())
+    }
+
+    /// Combines the cells of two maps of the same size. Panics if the sizes
+    /// differ.
+    pub fn zip_with<D, E, F: FnMut(&C, &D) -> E>(&self, other: &NodeMap<D>, mut f: F) -> NodeMap<E> {
+        assert_eq!(
+            (self.width, self.height),
+            (other.width, other.height),
+            "zipped maps differ in size"
+        );
+        let values = self.grid
+            .iter()
+            .zip(other.grid.iter())
+            .map(|(a, b)| f(a, b))
+            .collect();
+        NodeMap::from_vec(self.width, self.height, values)
+    }
+
+    pub fn count_where<F: FnMut(&C) -> bool>(&self, mut f: F) -> usize {
+        self.grid.iter().filter(|c| f(c)).count()
+    }
+}
+
+fn neighbours(
+    p: &(usize, usize),
+    offsets: &'static [(i32, i32)],
+    width: usize,
+    height: usize,
+) -> impl Iterator<Item = (usize, usize)> {
+    let (x, y) = (p.0 as i32, p.1 as i32);
+    offsets.iter().filter_map(move |&(i, j)| {
+        let (nx, ny) = (x + i, y + j);
+        if nx >= 0 && ny >= 0 && (nx as usize) < width && (ny as usize) < height {
+            Some((nx as usize, ny as usize))
+        } else {
+            None
+        }
+    })
 }
 
 impl<C: Clone> NodeMap<C> {
@@ -40,14 +176,86 @@ impl<C: Clone> NodeMap<C> {
     pub fn set(&mut self, p: &(usize, usize), value: C) {
         self.grid[p.1 * self.width + p.0] = value;
     }
+
+    /// Like `get`, but `None` instead of a panic or a value from the wrong
+    /// row when `p` is outside the map.
+    pub fn try_get(&self, p: &(usize, usize)) -> Option<C> {
+        if self.in_bounds(p) {
+            Some(self.get(p))
+        } else {
+            None
+        }
+    }
+
+    /// Like `set`, returns false and leaves the map alone when `p` is
+    /// outside it.
+    pub fn try_set(&mut self, p: &(usize, usize), value: C) -> bool {
+        if self.in_bounds(p) {
+            self.set(p, value);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn try_get_signed(&self, p: &(i32, i32)) -> Option<C> {
+        self.to_unsigned(p).map(|p| self.get(&p))
+    }
+
+    pub fn try_set_signed(&mut self, p: &(i32, i32), value: C) -> bool {
+        match self.to_unsigned(p) {
+            Some(p) => {
+                self.set(&p, value);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// One line per row with the cells written next to each other, so a
+/// `NodeMap<char>` comes out as the ASCII map it holds.
+impl<C: Display> Display for NodeMap<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in self.rows() {
+            for c in row {
+                write!(f, "{}", c)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the text written by `Display`: one line per row, all rows as
+/// wide as the first.
+impl FromStr for NodeMap<char> {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<NodeMap<char>, String> {
+        let rows: Vec<Vec<char>> = text.lines().map(|line| line.chars().collect()).collect();
+        let width = rows.first().map_or(0, |row| row.len());
+        if width == 0 {
+            return Err("map is empty".to_string());
+        }
+        if let Some(y) = rows.iter().position(|row| row.len() != width) {
+            return Err(format!(
+                "row {} is {} cells wide, expected {}",
+                y + 1,
+                rows[y].len(),
+                width
+            ));
+        }
+        Ok(NodeMap::from_vec(width, rows.len(), rows.concat()))
+    }
 }
 
 #[allow(dead_code)]
 impl<C: Debug + Clone> NodeMap<C> {
     pub fn print(&self) {
-        for j in 0..self.height {
-            for i in 0..self.width {
-                print!("{:?}", self.get(&(i, j)))
+        for row in self.rows() {
+            for c in row {
+                print!("{:?}", c)
             }
             println!();
         }
@@ -79,4 +287,118 @@ mod tests {
         new.print();
         assert_eq!(new.get(&(2, 0)), 1);
     }
+
+    #[test]
+    fn test_try_get_set() {
+        let mut nm = NodeMap::new(3, 2, 0);
+
+        assert!(nm.try_set(&(2, 1), 5));
+        assert!(!nm.try_set(&(3, 0), 5));
+        assert_eq!(nm.try_get(&(2, 1)), Some(5));
+        // (3, 0) would be (0, 1) when indexing the vec directly.
+        assert_eq!(nm.try_get(&(3, 0)), None);
+        assert_eq!(nm.try_get(&(0, 2)), None);
+
+        assert_eq!(nm.try_get_signed(&(-1, 0)), None);
+        assert_eq!(nm.try_get_signed(&(2, 1)), Some(5));
+        assert!(nm.try_set_signed(&(0, 0), 1));
+        assert!(!nm.try_set_signed(&(0, -1), 1));
+        assert_eq!(nm.get(&(0, 0)), 1);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let nm = NodeMap::new(4, 3, 0);
+
+        assert!(nm.in_bounds(&(3, 2)));
+        assert!(!nm.in_bounds(&(4, 2)));
+        assert!(nm.in_bounds_signed(&(0, 0)));
+        assert!(!nm.in_bounds_signed(&(-1, 1)));
+        assert_eq!(nm.to_unsigned(&(1, 2)), Some((1, 2)));
+        assert_eq!(nm.offset(&(0, 0), (-1, 0)), None);
+        assert_eq!(nm.offset(&(0, 0), (1, 1)), Some((1, 1)));
+    }
+
+    #[test]
+    fn test_neighbours() {
+        let nm = NodeMap::new(4, 3, 0);
+
+        assert_eq!(nm.neighbours4(&(1, 1)).count(), 4);
+        assert_eq!(nm.neighbours8(&(1, 1)).count(), 8);
+        assert_eq!(
+            nm.neighbours4(&(0, 0)).collect::<Vec<_>>(),
+            vec![(1, 0), (0, 1)]
+        );
+        assert_eq!(nm.neighbours8(&(3, 2)).count(), 3);
+        assert_eq!(nm.neighbours4(&(3, 1)).count(), 3);
+    }
+
+    #[test]
+    fn test_iterators() {
+        let mut nm = NodeMap::from_vec(3, 2, vec![1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(nm.iter().sum::<i32>(), 21);
+        assert_eq!(nm.enumerate().nth(4), Some(((1, 1), &5)));
+        assert_eq!(nm.rows().map(|r| r.to_vec()).collect::<Vec<_>>(), vec![vec![1, 2, 3], vec![4, 5, 6]]);
+        assert_eq!(nm.count_where(|&c| c % 2 == 0), 3);
+
+        for c in nm.iter_mut() {
+            *c *= 10;
+        }
+        for ((x, _), c) in nm.enumerate_mut() {
+            *c += x as i32;
+        }
+        assert_eq!(nm.get(&(2, 1)), 62);
+    }
+
+    #[test]
+    fn test_map_and_zip() {
+        let a = NodeMap::from_vec(2, 2, vec![1, 0, 0, 1]);
+        let b = NodeMap::from_vec(2, 2, vec![true, true, false, false]);
+
+        let chars = a.map(|&c| if c == 1 { '#' } else { '.' });
+        assert_eq!(chars.get(&(1, 1)), '#');
+        assert_eq!(chars.get(&(1, 0)), '.');
+
+        let both = a.zip_with(&b, |&x, &y| x == 1 && y);
+        assert_eq!(both.iter().cloned().collect::<Vec<_>>(), vec![true, false, false, false]);
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let text = "#####\n#..~#\n#####\n";
+        let nm: NodeMap<char> = text.parse().unwrap();
+
+        assert_eq!((nm.width, nm.height), (5, 3));
+        assert_eq!(nm.get(&(3, 1)), '~');
+        assert_eq!(nm.to_string(), text);
+        assert_eq!(nm.to_string().parse::<NodeMap<char>>().unwrap(), nm);
+        // The last newline is optional.
+        assert_eq!("#####\n#..~#\n#####".parse::<NodeMap<char>>().unwrap(), nm);
+
+        assert_eq!(NodeMap::from_vec(2, 1, vec![1, 0]).to_string(), "10\n");
+    }
+
+    #[test]
+    fn test_text_errors() {
+        assert!("".parse::<NodeMap<char>>().is_err());
+        assert!("\n\n".parse::<NodeMap<char>>().is_err());
+        assert!("###\n##\n".parse::<NodeMap<char>>().is_err());
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let nm: NodeMap<char> = "#.\n.#\n".parse().unwrap();
+        let json = ::serde_json::to_string(&nm).unwrap();
+
+        assert_eq!(::serde_json::from_str::<NodeMap<char>>(&json).unwrap(), nm);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zip_needs_same_size() {
+        let a = NodeMap::new(2, 2, 0);
+        let b = NodeMap::new(3, 2, 0);
+        a.zip_with(&b, |x, y| x + y);
+    }
 }
@@ -0,0 +1,241 @@
+use grid::{NodeMap, Rect};
+
+/// A borrowed rectangular window into a `NodeMap`. Coordinates are
+/// relative to the top left corner of the window.
+#[derive(Clone, Debug)]
+pub struct View<'a, C: 'a> {
+    map: &'a NodeMap<C>,
+    pub rect: Rect,
+}
+
+impl<'a, C: Clone> View<'a, C> {
+    pub fn width(&self) -> usize {
+        self.rect.w
+    }
+
+    pub fn height(&self) -> usize {
+        self.rect.h
+    }
+
+    pub fn in_bounds(&self, p: &(usize, usize)) -> bool {
+        p.0 < self.rect.w && p.1 < self.rect.h
+    }
+
+    pub fn get(&self, p: &(usize, usize)) -> C {
+        assert!(self.in_bounds(p), "{:?} is outside the view", p);
+        self.map.get(&(self.rect.x + p.0, self.rect.y + p.1))
+    }
+
+    pub fn try_get(&self, p: &(usize, usize)) -> Option<C> {
+        if self.in_bounds(p) {
+            Some(self.get(p))
+        } else {
+            None
+        }
+    }
+
+    /// One slice per row of the window, top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &'a [C]> + 'a {
+        let (map, rect) = (self.map, self.rect);
+        (rect.y..rect.y + rect.h).map(move |y| {
+            let start = y * map.width + rect.x;
+            &map.grid[start..start + rect.w]
+        })
+    }
+
+    /// Copies the window into a map of its own.
+    pub fn to_map(&self) -> NodeMap<C> {
+        let values = self.rows().flat_map(|row| row.iter().cloned()).collect();
+        NodeMap::from_vec(self.rect.w, self.rect.h, values)
+    }
+}
+
+impl<C: Clone> NodeMap<C> {
+    /// A map of the given size with `f` called for every cell.
+    pub fn from_fn<F: FnMut((usize, usize)) -> C>(width: usize, height: usize, mut f: F) -> NodeMap<C> {
+        let values = (0..width * height).map(|i| f((i % width, i / width))).collect();
+        NodeMap::from_vec(width, height, values)
+    }
+
+    /// The whole map as a rectangle.
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    fn assert_inside(&self, rect: &Rect) {
+        assert!(
+            rect.x + rect.w <= self.width && rect.y + rect.h <= self.height,
+            "{:?} is outside the {}x{} map",
+            rect,
+            self.width,
+            self.height
+        );
+    }
+
+    /// A window onto `rect`. Panics if `rect` reaches outside the map.
+    pub fn view(&self, rect: Rect) -> View<'_, C> {
+        self.assert_inside(&rect);
+        View { map: self, rect }
+    }
+
+    /// A copy of the cells in `rect`.
+    pub fn crop(&self, rect: Rect) -> NodeMap<C> {
+        self.view(rect).to_map()
+    }
+
+    /// A copy grown or shrunk to the new size. Cells keep their
+    /// coordinates, new ones are `fill`.
+    pub fn resize(&self, width: usize, height: usize, fill: C) -> NodeMap<C> {
+        NodeMap::from_fn(width, height, |p| self.try_get(&p).unwrap_or_else(|| fill.clone()))
+    }
+
+    /// Calls `f` with every cell of `other` and the cell it lands on when
+    /// the top left corner of `other` is put at `at`. Cells falling off
+    /// the map are skipped.
+    pub fn blit_with<D, F: FnMut(&mut C, &D)>(&mut self, other: &NodeMap<D>, at: (usize, usize), mut f: F) {
+        for (p, value) in other.enumerate() {
+            let target = (at.0 + p.0, at.1 + p.1);
+            if self.in_bounds(&target) {
+                let i = target.1 * self.width + target.0;
+                f(&mut self.grid[i], value);
+            }
+        }
+    }
+
+    /// Copies `other` onto the map with its top left corner at `at`.
+    pub fn blit(&mut self, other: &NodeMap<C>, at: (usize, usize)) {
+        self.blit_with(other, at, |cell, value| *cell = value.clone());
+    }
+
+    /// Like `blit`, but only copies the cells `mask` accepts, the others
+    /// leave the map underneath alone.
+    pub fn blit_masked<F: Fn(&C) -> bool>(&mut self, other: &NodeMap<C>, at: (usize, usize), mask: F) {
+        self.blit_with(other, at, |cell, value| {
+            if mask(value) {
+                *cell = value.clone();
+            }
+        });
+    }
+
+    /// Sets the cells within `thickness` of the border of `rect`. Panics
+    /// if `rect` reaches outside the map.
+    pub fn fill_border(&mut self, rect: Rect, thickness: usize, value: C) {
+        self.assert_inside(&rect);
+        for p in rect.border(thickness) {
+            self.set(&p, value.clone());
+        }
+    }
+
+    /// Turned a quarter clockwise.
+    pub fn rotate90(&self) -> NodeMap<C> {
+        let h = self.height;
+        NodeMap::from_fn(self.height, self.width, |(x, y)| self.get(&(y, h - 1 - x)))
+    }
+
+    pub fn rotate180(&self) -> NodeMap<C> {
+        let (w, h) = (self.width, self.height);
+        NodeMap::from_fn(w, h, |(x, y)| self.get(&(w - 1 - x, h - 1 - y)))
+    }
+
+    /// Turned a quarter counterclockwise.
+    pub fn rotate270(&self) -> NodeMap<C> {
+        let w = self.width;
+        NodeMap::from_fn(self.height, self.width, |(x, y)| self.get(&(w - 1 - y, x)))
+    }
+
+    /// Left and right swapped.
+    pub fn mirror(&self) -> NodeMap<C> {
+        let w = self.width;
+        NodeMap::from_fn(w, self.height, |(x, y)| self.get(&(w - 1 - x, y)))
+    }
+
+    /// Top and bottom swapped.
+    pub fn flip(&self) -> NodeMap<C> {
+        let h = self.height;
+        NodeMap::from_fn(self.width, h, |(x, y)| self.get(&(x, h - 1 - y)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> NodeMap<char> {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_view_and_crop() {
+        let nm = parse("abcd\nefgh\nijkl\n");
+        let view = nm.view(Rect::new(1, 1, 2, 2));
+
+        assert_eq!(view.width(), 2);
+        assert_eq!(view.get(&(0, 0)), 'f');
+        assert_eq!(view.try_get(&(2, 0)), None);
+        assert_eq!(view.rows().collect::<Vec<_>>(), vec![&['f', 'g'][..], &['j', 'k'][..]]);
+        assert_eq!(nm.crop(Rect::new(2, 0, 2, 3)), parse("cd\ngh\nkl\n"));
+        assert_eq!(nm.crop(nm.bounds()), nm);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_view_outside() {
+        parse("ab\ncd\n").view(Rect::new(1, 1, 2, 1));
+    }
+
+    #[test]
+    fn test_resize() {
+        let nm = parse("ab\ncd\n");
+
+        assert_eq!(nm.resize(3, 1, '.'), parse("ab.\n"));
+        assert_eq!(nm.resize(1, 3, '.'), parse("a\nc\n.\n"));
+    }
+
+    #[test]
+    fn test_blit() {
+        let mut nm = NodeMap::new(4, 3, '.');
+        let stamp = parse("x \n y\n");
+
+        nm.blit(&stamp, (1, 0));
+        assert_eq!(nm, parse(".x .\n. y.\n....\n"));
+
+        let mut nm = NodeMap::new(4, 3, '.');
+        nm.blit_masked(&stamp, (3, 1), |&c| c != ' ');
+        assert_eq!(nm, parse("....\n...x\n....\n"));
+
+        let mut count = NodeMap::new(2, 2, 0);
+        count.blit_with(&stamp, (0, 0), |cell, &c| *cell = if c == ' ' { 0 } else { 1 });
+        assert_eq!(count, NodeMap::from_vec(2, 2, vec![1, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_fill_border() {
+        let mut nm = NodeMap::new(7, 6, '.');
+        nm.fill_border(Rect::new(1, 1, 5, 4), 1, '#');
+
+        assert_eq!(nm, parse(".......\n.#####.\n.#...#.\n.#...#.\n.#####.\n.......\n"));
+
+        nm.fill_border(nm.bounds(), 3, '~');
+        assert_eq!(nm.count_where(|&c| c == '~'), 42);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_fill_border_outside() {
+        NodeMap::new(3, 3, '.').fill_border(Rect::new(1, 1, 3, 2), 1, '#');
+    }
+
+    #[test]
+    fn test_rotate_and_mirror() {
+        let nm = parse("abc\ndef\n");
+
+        assert_eq!(nm.rotate90(), parse("da\neb\nfc\n"));
+        assert_eq!(nm.rotate180(), parse("fed\ncba\n"));
+        assert_eq!(nm.rotate270(), parse("cf\nbe\nad\n"));
+        assert_eq!(nm.rotate90().rotate90(), nm.rotate180());
+        assert_eq!(nm.rotate90().rotate270(), nm);
+        assert_eq!(nm.mirror(), parse("cba\nfed\n"));
+        assert_eq!(nm.flip(), parse("def\nabc\n"));
+        assert_eq!(nm.mirror().flip(), nm.rotate180());
+    }
+}
@@ -0,0 +1,17 @@
+//! Map generation shared by the game and the `cavegen` tool.
+
+extern crate tcod;
+extern crate serde;
+extern crate rand;
+extern crate pathfinding;
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate serde_json;
+
+#[macro_use]
+extern crate derive_new;
+
+pub mod grid;
+pub mod mapgen;
@@ -1,5 +1,8 @@
 extern crate tcod;
 extern crate serde;
+extern crate rand;
+extern crate pathfinding;
+extern crate rogue_rs;
 
 //use time::PreciseTime;
 
@@ -15,10 +18,24 @@ extern crate serde_json;
 #[macro_use]
 extern crate derive_new;
 
+mod tilemap;
+mod dungeon;
+
 use std::fs::File;
 
 use recs::*;
 
+use rogue_rs::{grid, mapgen};
+use grid::{manhattan, BitGrid, DijkstraMap, NodeMap};
+
+use rand::{Rng, XorShiftRng};
+
+use pathfinding::astar;
+
+use tilemap::TileMap;
+use dungeon::Dungeon;
+use mapgen::{MapGenerator, SpawnKind, Terrain};
+
 use tcod::{Console, RootConsole, BackgroundFlag, FontType, FontLayout};
 use tcod::map::{FovAlgorithm, Map};
 use tcod::input::Key;
@@ -30,10 +47,22 @@ const MAP_HEIGHT: i32 = 50;
 const MAP_WIDTH: i32 = 80;
 const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true;
+// Remembered tiles are drawn with their base color scaled by this.
+const MEMORY_DIM: f32 = 0.35;
+// Damage taken for every step into lava.
+const LAVA_DAMAGE: i32 = 2;
+// Chance per turn that an idle monster sets off somewhere.
+const WANDER_CHANCE: f32 = 0.1;
+// See DijkstraMap::flee.
+const FLEE_SCALE: f32 = 1.2;
 
 
 #[derive(PartialEq, Serialize, Deserialize)]
-enum Action { Move, Attack, BlockedMove }
+enum Action { Move, Attack, BlockedMove, Open }
+
+// Where auto-travel takes the player, see auto_travel.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Travel { Explore, Exit }
 
 // Component Definitions
 #[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, new)]
@@ -86,12 +115,12 @@ struct Static {}
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize, new)]
 struct Fov{
-    fov: Vec<bool>
+    fov: BitGrid
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize, new)]
 struct SpatialMemory {
-    memory: Vec<bool>
+    memory: BitGrid
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -102,11 +131,80 @@ struct SightRange{
     range: i32
 }
 
-fn render(world: &recs::Ecs, con: &mut RootConsole){
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+struct Exit {}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+struct StairsUp {}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+enum DoorState { Open, Closed, Locked }
+
+// Closed and locked doors also carry Blocking and BlockSight, see spawn_door.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, new)]
+struct Door {
+    state: DoorState
+}
+
+// An item that unlocks one locked door.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+struct DoorKey {}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, new)]
+struct Keyring {
+    keys: i32
+}
+
+// Moves left to spend getting out of slow terrain, see enter_terrain.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, new)]
+struct Slowed {
+    turns: i32
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+enum AiState { Idle, Wandering, Hunting, Fleeing }
+
+// What a monster is up to, see monster_ai. `path` holds the cells still
+// ahead of a wandering monster, or of a hunting one on its way to where
+// it last saw the player.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize, new)]
+struct Ai {
+    state: AiState,
+    #[new(default)]
+    path: Vec<(i32, i32)>,
+}
+
+// What the monster AI keeps between turns: the distances to the player
+// that every hunting monster walks by, and the dice for wandering.
+struct AiContext {
+    chase: Option<DijkstraMap>,
+    rng: XorShiftRng,
+}
+
+impl AiContext {
+    fn new(seed: u64) -> AiContext {
+        AiContext { chase: None, rng: mapgen::seeded_rng(seed) }
+    }
+}
+
+fn render(world: &recs::Ecs, tiles: &TileMap, con: &mut RootConsole){
     let player = get_player(&world);
     let fov = world.get::<Fov>(player).unwrap().fov;
     let memory = world.get::<SpatialMemory>(player).unwrap().memory;
 
+    for y in 0..tiles.height() {
+        for x in 0..tiles.width() {
+            let glyph = tiles.get(x, y).unwrap().glyph();
+            let color = tiles.color(x, y);
+
+            if is_in_fov(&fov, x, y) {
+                con.put_char_ex(x, y, glyph, color, BLACK);
+            } else if is_in_fov(&memory, x, y) {
+                con.put_char_ex(x, y, glyph, color * MEMORY_DIM, BLACK);
+            }
+        }
+    }
+
     let components = component_filter!(Static, Position, Sprite);
     let mut statics = vec![];
     world.collect_with(&components, &mut statics);
@@ -152,11 +250,8 @@ fn render(world: &recs::Ecs, con: &mut RootConsole){
 
 }
 
-fn is_in_fov(vec: &Vec<bool>, x: i32, y: i32) -> bool {
-    if x < 0 || y < 0 || x >= MAP_WIDTH || y >= MAP_HEIGHT {
-        return false;
-    }
-    vec[(MAP_WIDTH * y + x) as usize]
+fn is_in_fov(fov: &BitGrid, x: i32, y: i32) -> bool {
+    fov.try_get_signed(&(x, y)).unwrap_or(false)
 }
 
 fn take_dmg(world: &mut Ecs) {
@@ -199,7 +294,7 @@ fn die(world: &mut Ecs, id: &EntityId){
     let _ = world.set(corpse, Sprite{ glyph: '%'});
 }
 
-fn calculate_fov(world: &mut Ecs){
+fn calculate_fov(world: &mut Ecs, tiles: &TileMap){
     let components = component_filter!(Position, Fov, SightRange);
     let mut to_update = Vec::new();
     world.collect_with(&components, &mut to_update);
@@ -209,7 +304,12 @@ fn calculate_fov(world: &mut Ecs){
     world.collect_with(&components2, &mut blocking);
 
     let mut fov_map = Map::new(MAP_WIDTH, MAP_HEIGHT);
-    fov_map.clear(true, true);
+
+    for y in 0..tiles.height() {
+        for x in 0..tiles.width() {
+            fov_map.set(x, y, !tiles.blocks_sight(x, y), tiles.is_walkable(x, y));
+        }
+    }
 
     for id in blocking.iter(){
         let pos = world.get::<Position>(*id).unwrap();
@@ -222,7 +322,7 @@ fn calculate_fov(world: &mut Ecs){
         fov_map.compute_fov(pos.x, pos.y, range, FOV_LIGHT_WALLS, FOV_ALGO);
         {
             let fov = world.borrow_mut::<Fov>(*id).unwrap();
-            fov.fov = map_to_vec(&fov_map);
+            fov.fov = map_to_bitgrid(&fov_map);
         }
         if world.has::<SpatialMemory>(*id).unwrap() {
             let fov = world.get::<Fov>(*id).unwrap();
@@ -232,26 +332,23 @@ fn calculate_fov(world: &mut Ecs){
     }
 }
 
-fn map_to_vec(map: &Map) -> Vec<bool>{
-    let mut vec :Vec<bool> = Vec::with_capacity((MAP_WIDTH * MAP_HEIGHT) as usize);
+fn map_to_bitgrid(map: &Map) -> BitGrid{
+    let mut fov = BitGrid::new(MAP_WIDTH as usize, MAP_HEIGHT as usize, false);
 
     for y in 0..MAP_HEIGHT {
         for x in 0..MAP_WIDTH {
-            vec.push(map.is_in_fov(x, y));
+            fov.set(&(x as usize, y as usize), map.is_in_fov(x, y));
         }
     }
-    vec
+    fov
 }
 
-fn compute_memory(memory: &mut Vec<bool>, fov: &Vec<bool>){
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH{
-            memory[(y * MAP_WIDTH + x) as usize] =  memory[(y * MAP_WIDTH + x) as usize] || fov[(y * MAP_WIDTH + x) as usize];
-        }
-    }
+fn compute_memory(memory: &mut BitGrid, fov: &BitGrid){
+    memory.union_with(fov);
 }
 
-fn move_or_attack(world: &mut Ecs){
+// Returns the entities that stepped onto another cell.
+fn move_or_attack(world: &mut Ecs, tiles: &TileMap) -> Vec<EntityId> {
 
     // Get entities which can move
     let components = component_filter!(Position, Velocity);
@@ -265,6 +362,12 @@ fn move_or_attack(world: &mut Ecs){
     world.collect_with(&positions, &mut to_go);
 
 
+    // Doors and keys change after everyone moved, rebuilding a door
+    // entity would pull it out from under the loops below.
+    let mut doors = vec![];
+    let mut picked_up = vec![];
+    let mut moved = vec![];
+
     for id in to_update.iter(){
 
         let new_x;
@@ -273,8 +376,15 @@ fn move_or_attack(world: &mut Ecs){
         let vel: Velocity = world.get(*id).unwrap();
         let pos: Position = world.get(*id).unwrap();
 
-        if pos.x + vel.x >= 0 && pos.x + vel.x < MAP_WIDTH &&
-            pos.y + vel.y >= 0 && pos.y + vel.y < MAP_HEIGHT {
+        // A slowed entity spends its move wading instead.
+        let slowed = world.get::<Slowed>(*id).map_or(0, |s| s.turns);
+        if slowed > 0 && (vel.x != 0 || vel.y != 0) {
+            world.borrow_mut::<Slowed>(*id).unwrap().turns -= 1;
+            let _ = world.set(*id, Velocity{x: 0, y: 0});
+            continue;
+        }
+
+        if tiles.in_bounds(pos.x + vel.x, pos.y + vel.y) {
             new_x = vel.x + pos.x;
             new_y = vel.y + pos.y;
         }
@@ -284,7 +394,11 @@ fn move_or_attack(world: &mut Ecs){
             }
 
         // Default action if no-one is on the way
-        let mut action = Action::Move;
+        let mut action = if tiles.is_walkable(new_x, new_y) {
+            Action::Move
+        } else {
+            Action::BlockedMove
+        };
 
         for id_other in to_go.iter(){
             let pos_other : Position = world.get(*id_other).unwrap();
@@ -292,9 +406,34 @@ fn move_or_attack(world: &mut Ecs){
                 if world.has::<TakeDamage>(*id_other).unwrap() {
                     action = Action::Attack;
 
-                    println!("You hit {}", world.get::<Name>(*id_other).unwrap().name);
+                    let target = world.get::<Name>(*id_other).unwrap().name;
+                    if world.has::<Player>(*id).unwrap() {
+                        println!("You hit {}", target);
+                    } else if world.has::<Player>(*id_other).unwrap() {
+                        println!("{} hits you", world.get::<Name>(*id).unwrap().name);
+                    } else {
+                        println!("{} hits {}", world.get::<Name>(*id).unwrap().name, target);
+                    }
                     let damage = world.get::<Damage>(*id).unwrap().dmg;
                     world.borrow_mut::<TakeDamage>(*id_other).unwrap().dmg.push(damage);
+                } else if let Ok(door) = world.get::<Door>(*id_other) {
+                    match door.state {
+                        DoorState::Open => (),
+                        DoorState::Closed => {
+                            action = Action::Open;
+                            doors.push((*id_other, DoorState::Open));
+                        },
+                        DoorState::Locked => {
+                            if use_key(world, *id) {
+                                println!("You unlock the door.");
+                                action = Action::Open;
+                                doors.push((*id_other, DoorState::Open));
+                            } else {
+                                println!("The door is locked.");
+                                action = Action::BlockedMove;
+                            }
+                        },
+                    }
                 } else if world.has::<Blocking>(*id_other).unwrap(){
                     action = Action::BlockedMove;
                 }
@@ -303,94 +442,593 @@ fn move_or_attack(world: &mut Ecs){
 
         if action == Action::Move {
             let _ = world.set(*id, Position { x: new_x, y: new_y });
+            if new_x != pos.x || new_y != pos.y {
+                moved.push(*id);
+            }
+
+            if world.has::<Keyring>(*id).unwrap() {
+                for id_other in to_go.iter() {
+                    let pos_other: Position = world.get(*id_other).unwrap();
+                    let is_key = world.has::<DoorKey>(*id_other).unwrap();
+                    if pos_other.x == new_x && pos_other.y == new_y && is_key {
+                        picked_up.push((*id, *id_other));
+                    }
+                }
+            }
         }
 
         let _ = world.set(*id, Velocity{x: 0, y: 0});
 
 
     }
-}
 
-fn get_player(world: &Ecs) -> EntityId{
-    let components = component_filter!(Player);
-    let mut to_update = vec![];
-    world.collect_with(&components, &mut to_update);
-    to_update[0]
-}
+    for (door, state) in doors {
+        if world.exists(door) {
+            set_door_state(world, door, state);
+        }
+    }
 
-fn save(world: &Ecs){
+    for (id, key) in picked_up {
+        if world.exists(key) {
+            let _ = world.destroy_entity(key);
+            world.borrow_mut::<Keyring>(id).unwrap().keys += 1;
+            println!("You pick up a key.");
+        }
+    }
 
-    let mut vec:Vec<Vec<serde_json::value::Value>> = vec![];
+    moved
+}
 
-    for id in world.iter(){
-        let mut vec_inner : Vec<serde_json::Value> = vec![];
+// Applies the terrain the moved entities stepped onto. Slow terrain costs
+// extra moves, lava burns and a chasm swallows monsters. Returns true if
+// the player fell into a chasm and has to go down a level.
+fn enter_terrain(world: &mut Ecs, tiles: &TileMap, moved: &[EntityId]) -> bool {
+    let mut fell = false;
 
-        match world.get::<Position>(id) {
-            Ok(component) => vec_inner.push(json!({"Position": component})),
-            _ => ()
-        }
-        match world.get::<Velocity>(id) {
-            Ok(component) => vec_inner.push(json!({"Velocity": component})),
-            _ => ()
+    for id in moved.iter() {
+        let pos: Position = world.get(*id).unwrap();
+        let terrain = tiles.terrain(pos.x, pos.y);
+        let name = world.get::<Name>(*id).map_or(String::new(), |n| n.name);
+
+        if terrain.move_cost() > 1 {
+            let _ = world.set(*id, Slowed::new(terrain.move_cost() - 1));
         }
-        match world.get::<Name>(id) {
-            Ok(component) => vec_inner.push(json!({"Name": component})),
-            _ => ()
+
+        match terrain {
+            Terrain::Lava => {
+                if let Ok(take_dmg) = world.borrow_mut::<TakeDamage>(*id) {
+                    take_dmg.dmg.push(LAVA_DAMAGE);
+                }
+                println!("{} is burned by lava.", name);
+            },
+            Terrain::Chasm => {
+                if world.has::<Player>(*id).unwrap() {
+                    fell = true;
+                } else {
+                    println!("{} falls into the chasm.", name);
+                    let _ = world.destroy_entity(*id);
+                }
+            },
+            _ => (),
         }
-        match world.get::<TakeDamage>(id) {
-            Ok(component) => vec_inner.push(json!({"TakeDamage": component})),
-            _ => ()
+    }
+    fell
+}
+
+// Runs everything that follows the player's move: the monsters' moves,
+// movement, terrain, damage and field of view. Returns false once the
+// player is dead.
+fn play_turn(
+    world: &mut Ecs,
+    dungeon: &mut Dungeon,
+    generator: &dyn MapGenerator,
+    ai: &mut AiContext,
+) -> bool {
+    monster_ai(world, dungeon.map(), ai);
+    let moved = move_or_attack(world, dungeon.map());
+    if enter_terrain(world, dungeon.map(), &moved) {
+        let depth = dungeon.depth + 1;
+        dungeon::change_level(world, dungeon, generator, &GameEntities, depth);
+        println!("You fall down to depth {}.", depth);
+    }
+    take_dmg(world);
+
+    let mut alive = vec![];
+    world.collect_with(&component_filter!(Player), &mut alive);
+    if alive.is_empty() {
+        println!("You died.");
+        return false;
+    }
+
+    calculate_fov(world, dungeon.map());
+    true
+}
+
+// Locked doors cannot be travelled through without one of `keys`, the
+// others are opened by walking into them.
+fn door_costs(world: &Ecs, keys: i32) -> Vec<((usize, usize), Option<i32>)> {
+    let mut doors = vec![];
+    world.collect_with(&component_filter!(Door, Position), &mut doors);
+
+    doors.iter().map(|id| {
+        let pos: Position = world.get(*id).unwrap();
+        let locked = world.get::<Door>(*id).unwrap().state == DoorState::Locked;
+        let cost = if locked && keys == 0 { None } else { Some(1) };
+        ((pos.x as usize, pos.y as usize), cost)
+    }).collect()
+}
+
+fn player_keys(world: &Ecs) -> i32 {
+    world.get::<Keyring>(get_player(world)).map(|k| k.keys).unwrap_or(0)
+}
+
+fn travel_costs(world: &Ecs, tiles: &TileMap, keys: i32) -> NodeMap<Option<i32>> {
+    let mut costs = tiles.move_costs();
+    for (p, cost) in door_costs(world, keys) {
+        costs.try_set(&p, cost);
+    }
+    costs
+}
+
+// The exit, or every cell the player could walk on but does not remember.
+fn travel_goals(world: &Ecs, tiles: &TileMap, travel: Travel) -> Vec<(usize, usize)> {
+    match travel {
+        Travel::Exit => {
+            let mut exits = vec![];
+            world.collect_with(&component_filter!(Exit, Position), &mut exits);
+            exits.iter().map(|id| {
+                let pos: Position = world.get(*id).unwrap();
+                (pos.x as usize, pos.y as usize)
+            }).collect()
+        },
+        Travel::Explore => {
+            let memory = world.get::<SpatialMemory>(get_player(world)).unwrap().memory;
+            tiles.move_costs()
+                .enumerate()
+                .filter(|&(p, cost)| cost.is_some() && !memory.try_get(&p).unwrap_or(false))
+                .map(|(p, _)| p)
+                .collect()
+        },
+    }
+}
+
+// Points the player one step down the map, false if no step gets closer
+// to a goal.
+fn travel_step(world: &mut Ecs, map: &DijkstraMap) -> bool {
+    let player = get_player(world);
+    let pos: Position = world.get(player).unwrap();
+    match map.downhill(&(pos.x as usize, pos.y as usize)) {
+        Some((x, y)) => {
+            let _ = world.set(player, Velocity::new(x as i32 - pos.x, y as i32 - pos.y));
+            true
+        },
+        None => false,
+    }
+}
+
+fn monster_in_view(world: &Ecs) -> bool {
+    let fov = world.get::<Fov>(get_player(world)).unwrap().fov;
+    let mut monsters = vec![];
+    world.collect_with(&component_filter!(Position, TakeDamage), &mut monsters);
+
+    monsters.iter().any(|id| {
+        let pos: Position = world.get(*id).unwrap();
+        !world.has::<Player>(*id).unwrap() && is_in_fov(&fov, pos.x, pos.y)
+    })
+}
+
+// Walks the player towards the goals of `travel` a turn at a time until
+// they are reached, the way is blocked, a monster comes into view or the
+// level changes. Returns false if the player died on the way.
+fn auto_travel(
+    world: &mut Ecs,
+    dungeon: &mut Dungeon,
+    generator: &dyn MapGenerator,
+    con: &mut RootConsole,
+    ai: &mut AiContext,
+    travel: Travel,
+) -> bool {
+    let depth = dungeon.depth;
+    let goals = travel_goals(world, dungeon.map(), travel);
+    let keys = player_keys(world);
+    let mut map = DijkstraMap::new(travel_costs(world, dungeon.map(), keys), &goals);
+
+    while !monster_in_view(world) && travel_step(world, &map) {
+        if !play_turn(world, dungeon, generator, ai) {
+            return false;
         }
-        match world.get::<Health>(id) {
-            Ok(component) => vec_inner.push(json!({"Health": component})),
-            _ => ()
+        if dungeon.depth != depth {
+            return true;
         }
-        match world.get::<Blocking>(id) {
-            Ok(component) => vec_inner.push(json!({"Blocking": component})),
-            _ => ()
+        render(world, dungeon.map(), con);
+        con.flush();
+
+        // Keys picked up on the way open locked doors, and every step
+        // uncovers cells that no longer need exploring.
+        for (p, cost) in door_costs(world, player_keys(world)) {
+            if map.cost(&p) != cost {
+                map.set_cost(&p, cost);
+            }
         }
-        match world.get::<Sprite>(id) {
-            Ok(component) => vec_inner.push(json!({"Sprite": component})),
-            _ => ()
+        map.set_goals(&travel_goals(world, dungeon.map(), travel));
+    }
+
+    if travel == Travel::Explore && travel_goals(world, dungeon.map(), travel).is_empty() {
+        println!("Nothing left to explore.");
+    }
+    true
+}
+
+// Follows the player around, and the doors as they open and close, by
+// repairing the map instead of building it again every turn.
+fn update_chase_map(world: &Ecs, costs: &NodeMap<Option<i32>>, chase: &mut Option<DijkstraMap>) {
+    let pos: Position = world.get(get_player(world)).unwrap();
+    let goal = [(pos.x as usize, pos.y as usize)];
+    if let Some(ref mut map) = *chase {
+        let size = (map.values().width, map.values().height);
+        if size == (costs.width, costs.height) {
+            map.set_costs(costs.clone());
+            map.set_goals(&goal);
+            return;
         }
-        match world.get::<Damage>(id) {
-            Ok(component) => vec_inner.push(json!({"Damage": component})),
-            _ => ()
+    }
+    *chase = Some(DijkstraMap::new(costs.clone(), &goal));
+}
+
+// A route to a random cell the monster can walk to, not including the
+// cell it starts from.
+fn wander_route<R: Rng>(costs: &NodeMap<Option<i32>>, from: (usize, usize), rng: &mut R) -> Option<Vec<(i32, i32)>> {
+    let to = (rng.gen_range(0, costs.width), rng.gen_range(0, costs.height));
+    if to == from || costs.get(&to).is_none() {
+        return None;
+    }
+
+    let (path, _) = astar(
+        &from,
+        |p| costs.neighbours4(p)
+            .filter_map(|n| costs.get(&n).map(|cost| (n, cost)))
+            .collect::<Vec<_>>(),
+        |&(x, y)| manhattan((x as i32, y as i32), (to.0 as i32, to.1 as i32)),
+        |p| *p == to,
+    )?;
+    Some(path.into_iter().skip(1).map(|(x, y)| (x as i32, y as i32)).collect())
+}
+
+// Sets the Velocity of every monster for move_or_attack. A monster that
+// sees the player hunts it down, or runs once its health is down to a
+// third. Out of sight it walks to where it last saw the player, then
+// idles and now and then wanders off somewhere. Monsters only step into
+// cells no other monster is in or heading for, so they never attack each
+// other.
+fn monster_ai(world: &mut Ecs, tiles: &TileMap, ai: &mut AiContext) {
+    let mut monsters = vec![];
+    world.collect_with(&component_filter!(Ai, Position, Velocity), &mut monsters);
+    if monsters.is_empty() {
+        return;
+    }
+
+    let costs = travel_costs(world, tiles, 0);
+    update_chase_map(world, &costs, &mut ai.chase);
+    let chase = ai.chase.as_ref().unwrap();
+    let mut flee = None;
+
+    let player: Position = world.get(get_player(world)).unwrap();
+    let mut occupied: Vec<(i32, i32)> = monsters.iter().map(|id| {
+        let pos: Position = world.get(*id).unwrap();
+        (pos.x, pos.y)
+    }).collect();
+
+    for id in monsters.iter() {
+        let pos: Position = world.get(*id).unwrap();
+        let here = (pos.x as usize, pos.y as usize);
+        let mut mind: Ai = world.get(*id).unwrap();
+
+        let sees_player = world.get::<Fov>(*id).map(|f| is_in_fov(&f.fov, player.x, player.y)).unwrap_or(false);
+        let hurt = world.get::<Health>(*id).map(|h| h.hp * 3 <= h.max).unwrap_or(false);
+
+        if sees_player && hurt {
+            mind.state = AiState::Fleeing;
+            mind.path.clear();
+        } else if sees_player {
+            mind.state = AiState::Hunting;
+            mind.path = chase.path(&here).into_iter().map(|(x, y)| (x as i32, y as i32)).collect();
+        } else if mind.state == AiState::Fleeing {
+            mind.state = AiState::Idle;
         }
-        match world.get::<Player>(id) {
-            Ok(component) => vec_inner.push(json!({"Player": component})),
-            _ => ()
+
+        while mind.path.first() == Some(&(pos.x, pos.y)) {
+            mind.path.remove(0);
         }
-        match world.get::<Static>(id) {
-            Ok(component) => vec_inner.push(json!({"Static": component})),
-            _ => ()
+        if mind.path.is_empty() && mind.state != AiState::Fleeing {
+            mind.state = AiState::Idle;
         }
-        match world.get::<Fov>(id) {
-            Ok(component) => vec_inner.push(json!({"Fov": component})),
-            _ => ()
+        if mind.state == AiState::Idle && ai.rng.gen::<f32>() < WANDER_CHANCE {
+            if let Some(path) = wander_route(&costs, here, &mut ai.rng) {
+                mind.state = AiState::Wandering;
+                mind.path = path;
+            }
         }
-        match world.get::<SpatialMemory>(id) {
-            Ok(component) => vec_inner.push(json!({"SpatialMemory": component})),
-            _ => ()
+
+        let step = if mind.state == AiState::Fleeing {
+            flee.get_or_insert_with(|| chase.flee(FLEE_SCALE))
+                .downhill(&here)
+                .map(|(x, y)| (x as i32, y as i32))
+        } else {
+            mind.path.first().cloned()
+        };
+
+        let mut velocity = Velocity::new(0, 0);
+        if let Some(next) = step {
+            let blocked = costs.try_get_signed(&next).and_then(|cost| cost).is_none();
+            if blocked {
+                // A door locked in front of it, look for something else to do.
+                mind.path.clear();
+                mind.state = AiState::Idle;
+            } else if !occupied.contains(&next) {
+                // The cell it leaves stays taken, it may be slowed, bump a
+                // door or attack instead of moving.
+                velocity = Velocity::new(next.0 - pos.x, next.1 - pos.y);
+                occupied.push(next);
+            }
         }
-        match world.get::<BlockSight>(id) {
-            Ok(component) => vec_inner.push(json!({"BlockSight": component})),
-            _ => ()
+
+        let _ = world.set(*id, velocity);
+        let _ = world.set(*id, mind);
+    }
+}
+
+// Takes one key from the entity's keyring, false if it has none.
+fn use_key(world: &mut Ecs, id: EntityId) -> bool {
+    match world.borrow_mut::<Keyring>(id) {
+        Ok(ref mut keyring) if keyring.keys > 0 => {
+            keyring.keys -= 1;
+            true
+        },
+        _ => false,
+    }
+}
+
+fn spawn_door(world: &mut Ecs, x: i32, y: i32, state: DoorState) -> EntityId {
+    let door = world.create_entity();
+    let _ = world.set(door, Position::new(x, y));
+    let _ = world.set(door, Static {});
+    let _ = world.set(door, Name::new("door".to_string()));
+    let _ = world.set(door, Door::new(state));
+    if state == DoorState::Open {
+        let _ = world.set(door, Sprite::new('\''));
+    } else {
+        let _ = world.set(door, Sprite::new('+'));
+        let _ = world.set(door, Blocking {});
+        let _ = world.set(door, BlockSight {});
+    }
+    door
+}
+
+// Components can't be taken off an entity, so the door is spawned anew
+// with the components of its new state.
+fn set_door_state(world: &mut Ecs, door: EntityId, state: DoorState) -> EntityId {
+    let pos: Position = world.get(door).unwrap();
+    let _ = world.destroy_entity(door);
+    spawn_door(world, pos.x, pos.y, state)
+}
+
+// Closes the open doors next to the player that nothing stands in.
+fn close_doors(world: &mut Ecs) {
+    let player = get_player(world);
+    let pos: Position = world.get(player).unwrap();
+
+    let mut positioned = vec![];
+    world.collect_with(&component_filter!(Position), &mut positioned);
+
+    let mut to_close = vec![];
+    for id in positioned.iter() {
+        let door = match world.get::<Door>(*id) {
+            Ok(door) => door,
+            _ => continue,
+        };
+        let door_pos: Position = world.get(*id).unwrap();
+        let adjacent = (door_pos.x - pos.x).abs() + (door_pos.y - pos.y).abs() == 1;
+        let occupied = positioned.iter().any(|other| {
+            *other != *id && world.get::<Position>(*other).unwrap() == door_pos
+        });
+        if door.state == DoorState::Open && adjacent && !occupied {
+            to_close.push(*id);
         }
-        match world.get::<SightRange>(id) {
-            Ok(component) => vec_inner.push(json!({"SightRange": component})),
+    }
+
+    for id in to_close {
+        set_door_state(world, id, DoorState::Closed);
+        println!("You close the door.");
+    }
+}
+
+fn get_player(world: &Ecs) -> EntityId{
+    let components = component_filter!(Player);
+    let mut to_update = vec![];
+    world.collect_with(&components, &mut to_update);
+    to_update[0]
+}
+
+fn entity_to_json(world: &Ecs, id: EntityId) -> Vec<serde_json::Value> {
+    let mut vec_inner : Vec<serde_json::Value> = vec![];
+
+    match world.get::<Position>(id) {
+        Ok(component) => vec_inner.push(json!({"Position": component})),
+        _ => ()
+    }
+    match world.get::<Velocity>(id) {
+        Ok(component) => vec_inner.push(json!({"Velocity": component})),
+        _ => ()
+    }
+    match world.get::<Name>(id) {
+        Ok(component) => vec_inner.push(json!({"Name": component})),
+        _ => ()
+    }
+    match world.get::<TakeDamage>(id) {
+        Ok(component) => vec_inner.push(json!({"TakeDamage": component})),
+        _ => ()
+    }
+    match world.get::<Health>(id) {
+        Ok(component) => vec_inner.push(json!({"Health": component})),
+        _ => ()
+    }
+    match world.get::<Blocking>(id) {
+        Ok(component) => vec_inner.push(json!({"Blocking": component})),
+        _ => ()
+    }
+    match world.get::<Sprite>(id) {
+        Ok(component) => vec_inner.push(json!({"Sprite": component})),
+        _ => ()
+    }
+    match world.get::<Damage>(id) {
+        Ok(component) => vec_inner.push(json!({"Damage": component})),
+        _ => ()
+    }
+    match world.get::<Player>(id) {
+        Ok(component) => vec_inner.push(json!({"Player": component})),
+        _ => ()
+    }
+    match world.get::<Static>(id) {
+        Ok(component) => vec_inner.push(json!({"Static": component})),
+        _ => ()
+    }
+    match world.get::<Fov>(id) {
+        Ok(component) => vec_inner.push(json!({"Fov": component})),
+        _ => ()
+    }
+    match world.get::<SpatialMemory>(id) {
+        Ok(component) => vec_inner.push(json!({"SpatialMemory": component})),
+        _ => ()
+    }
+    match world.get::<BlockSight>(id) {
+        Ok(component) => vec_inner.push(json!({"BlockSight": component})),
+        _ => ()
+    }
+    match world.get::<SightRange>(id) {
+        Ok(component) => vec_inner.push(json!({"SightRange": component})),
+        _ => ()
+    }
+    match world.get::<Exit>(id) {
+        Ok(component) => vec_inner.push(json!({"Exit": component})),
+        _ => ()
+    }
+    match world.get::<StairsUp>(id) {
+        Ok(component) => vec_inner.push(json!({"StairsUp": component})),
+        _ => ()
+    }
+    match world.get::<Door>(id) {
+        Ok(component) => vec_inner.push(json!({"Door": component})),
+        _ => ()
+    }
+    match world.get::<DoorKey>(id) {
+        Ok(component) => vec_inner.push(json!({"DoorKey": component})),
+        _ => ()
+    }
+    match world.get::<Keyring>(id) {
+        Ok(component) => vec_inner.push(json!({"Keyring": component})),
+        _ => ()
+    }
+    match world.get::<Slowed>(id) {
+        Ok(component) => vec_inner.push(json!({"Slowed": component})),
+        _ => ()
+    }
+    match world.get::<Ai>(id) {
+        Ok(component) => vec_inner.push(json!({"Ai": component})),
+        _ => ()
+    }
+
+    vec_inner
+}
+
+fn entity_from_json(world: &mut Ecs, components: &[serde_json::Value]) -> EntityId {
+    let new = world.create_entity();
+    for val in components.iter(){
+        let key : &String = val.as_object().unwrap().keys().collect::<Vec<_>>()[0];
+
+        match key.as_ref(){
+            "Position" => {
+                let _ = world.set::<Position>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "Velocity" => {
+                let _ = world.set::<Velocity>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            }
+            "Sprite" => {
+                let _ = world.set::<Sprite>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            }
+            "Name" => {
+                let _ = world.set::<Name>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "TakeDamage" => {
+                let _ = world.set::<TakeDamage>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "Health" => {
+                let _ = world.set::<Health>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "Blocking" => {
+                let _ = world.set::<Blocking>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "Damage" => {
+                let _ = world.set::<Damage>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "Player" => {
+                let _ = world.set::<Player>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "Static" => {
+                let _ = world.set::<Static>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "Fov" => {
+                let _ = world.set::<Fov>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "SpatialMemory" => {
+                let _ = world.set::<SpatialMemory>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "BlockSight" => {
+                let _ = world.set::<BlockSight>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "SightRange" => {
+                let _ = world.set::<SightRange>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "Exit" => {
+                let _ = world.set::<Exit>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            },
+            "StairsUp" => {
+                let _ = world.set::<StairsUp>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            }
+            "Door" => {
+                let _ = world.set::<Door>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            }
+            "DoorKey" => {
+                let _ = world.set::<DoorKey>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            }
+            "Keyring" => {
+                let _ = world.set::<Keyring>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            }
+            "Slowed" => {
+                let _ = world.set::<Slowed>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            }
+            "Ai" => {
+                let _ = world.set::<Ai>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
+            }
             _ => ()
         }
+    }
+    new
+}
 
-        vec.push(vec_inner);
+fn save(world: &Ecs, dungeon: &Dungeon){
+
+    let mut vec:Vec<Vec<serde_json::value::Value>> = vec![];
+
+    for id in world.iter(){
+        vec.push(entity_to_json(world, id));
     }
     let buffer = File::create("foo.txt").unwrap();
     println!("Game saved!");
-    let _ = serde_json::to_writer(buffer, &json!(vec));;
+    let _ = serde_json::to_writer(buffer, &json!({"dungeon": dungeon, "entities": vec}));
 
 }
 
-fn load(world: &mut Ecs){
+fn load(world: &mut Ecs, dungeon: &mut Dungeon){
     let mut ids = vec![];
     world.collect(&mut ids);
 
@@ -400,129 +1038,216 @@ fn load(world: &mut Ecs){
 
     let buffer = File::open("foo.txt").unwrap();
     let json: serde_json::Value = serde_json::from_reader(buffer).unwrap();
-    let v = json.as_array().unwrap();
-    for i in 0..v.len(){
-        let new = world.create_entity();
-        for val in v[i].as_array().unwrap().iter(){
-            let key : &String = val.as_object().unwrap().keys().collect::<Vec<_>>()[0];
-
-            match key.as_ref(){
-                "Position" => {
-                    let _ = world.set::<Position>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "Velocity" => {
-                    let _ = world.set::<Velocity>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                }
-                "Sprite" => {
-                    let _ = world.set::<Sprite>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                }
-                "Name" => {
-                    let _ = world.set::<Name>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "TakeDamage" => {
-                    let _ = world.set::<TakeDamage>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "Health" => {
-                    let _ = world.set::<Health>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "Blocking" => {
-                    let _ = world.set::<Blocking>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "Damage" => {
-                    let _ = world.set::<Damage>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "Player" => {
-                    let _ = world.set::<Player>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "Static" => {
-                    let _ = world.set::<Static>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "Fov" => {
-                    let _ = world.set::<Fov>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "SpatialMemory" => {
-                    let _ = world.set::<SpatialMemory>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "BlockSight" => {
-                    let _ = world.set::<BlockSight>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                },
-                "SightRange" => {
-                    let _ = world.set::<SightRange>(new, serde_json::from_value(val.as_object().unwrap()[key].clone()).unwrap());
-                }
-                _ => ()
-            }
-        }
-
+    *dungeon = serde_json::from_value(json["dungeon"].clone()).unwrap();
+    for components in json["entities"].as_array().unwrap().iter(){
+        entity_from_json(world, components.as_array().unwrap());
     }
     println!("Game loaded!");
 }
 
-fn main() {
-    let mut con = RootConsole::initializer()
-        .font("arial10x10.png", FontLayout::Tcod)
-        .font_type(FontType::Greyscale)
-        .size(MAP_WIDTH, MAP_HEIGHT)
-        .title("ecs test")
-        .init();
+fn spawn_level(world: &mut Ecs, cave: &mapgen::MapInfo, depth: usize) {
+    let (end_x, end_y) = cave.end;
+    let exit = world.create_entity();
+    let _ = world.set(exit, Position::new(end_x as i32, end_y as i32));
+    let _ = world.set(exit, Exit {});
+    let _ = world.set(exit, Static {});
+    let _ = world.set(exit, Name::new("exit".to_string()));
+    let _ = world.set(exit, Sprite { glyph: '>' });
+
+    if depth > 0 {
+        let (start_x, start_y) = cave.start;
+        let stairs = world.create_entity();
+        let _ = world.set(stairs, Position::new(start_x as i32, start_y as i32));
+        let _ = world.set(stairs, StairsUp {});
+        let _ = world.set(stairs, Static {});
+        let _ = world.set(stairs, Name::new("stairs up".to_string()));
+        let _ = world.set(stairs, Sprite { glyph: '<' });
+    }
 
-    let mut world = Ecs::new();
-    let walls = vec![(1, 3), (2, 3), (3, 3), (4, 3), (5, 3)];
-
-    for x in 0..MAP_WIDTH {
-        for y in 0..MAP_HEIGHT{
-            if !walls.contains(&(x, y)) {
-                let floor = world.create_entity();
-                let _ = world.set(floor, Position::new(x, y));
-                let _ = world.set(floor, Static {});
-                let _ = world.set(floor, Sprite { glyph: '.' });
-            }
+    for spawn in cave.spawns.iter() {
+        let (x, y) = (spawn.pos.0 as i32, spawn.pos.1 as i32);
+        match spawn.kind {
+            SpawnKind::Monster(ref name) => {
+                spawn_monster(world, name, spawn.glyph, x, y);
+            },
+            SpawnKind::Item(ref name) => {
+                let item = world.create_entity();
+                let _ = world.set(item, Position::new(x, y));
+                let _ = world.set(item, Sprite::new(spawn.glyph));
+                let _ = world.set(item, Name::new(name.clone()));
+            },
+            SpawnKind::Door => {
+                spawn_door(world, x, y, DoorState::Closed);
+            },
+            SpawnKind::LockedDoor => {
+                spawn_door(world, x, y, DoorState::Locked);
+            },
+            SpawnKind::Key => {
+                let key = world.create_entity();
+                let _ = world.set(key, Position::new(x, y));
+                let _ = world.set(key, Sprite::new(spawn.glyph));
+                let _ = world.set(key, Name::new("key".to_string()));
+                let _ = world.set(key, DoorKey {});
+            },
         }
     }
+}
+
+/// Generated levels get a monster the map itself does not ask for, if
+/// there is floor left for it.
+fn spawn_wanderer<R: Rng>(world: &mut Ecs, cave: &mapgen::MapInfo, rng: &mut R) {
+    if let Some((monster_x, monster_y)) = random_floor(cave, rng) {
+        spawn_monster(world, "Gorok", 'm', monster_x as i32, monster_y as i32);
+    }
+}
 
-    let mut player = world.create_entity();
+fn spawn_monster(world: &mut Ecs, name: &str, glyph: char, x: i32, y: i32) -> EntityId {
     let monster = world.create_entity();
+    let _ = world.set(monster, Velocity::new(0,0));
+    let _ = world.set(monster, Position::new(x, y));
+    let _ = world.set(monster, Sprite::new(glyph));
+    let _ = world.set(monster, Health::new(2, 5));
+    let _ = world.set(monster, TakeDamage::new());
+    let _ = world.set(monster, Damage::new(1));
+    let _ = world.set(monster, Name::new(name.to_string()));
+    let _ = world.set(monster, Fov::new(BitGrid::new(MAP_WIDTH as usize, MAP_HEIGHT as usize, false)));
+    let _ = world.set(monster, SightRange::new(6));
+    let _ = world.set(monster, Ai::new(AiState::Idle));
+    monster
+}
 
-    let _ = world.set(player, Position::new(1, 1));
+fn spawn_player(world: &mut Ecs, x: i32, y: i32) -> EntityId {
+    let player = world.create_entity();
+    let _ = world.set(player, Position::new(x, y));
     let _ = world.set(player, Player{});
+    let _ = world.set(player, Name::new("player".to_string()));
+    let _ = world.set(player, Health::new(10, 10));
+    let _ = world.set(player, TakeDamage::new());
     let _ = world.set(player, Damage::new(1));
     let _ = world.set(player, Velocity::new(0,0));
     let _ = world.set(player, Sprite::new('@'));
-    let _ = world.set(player, Fov::new(vec![false; (MAP_HEIGHT * MAP_WIDTH) as usize]));
-    let _ = world.set(player, SpatialMemory::new(vec![false; (MAP_HEIGHT * MAP_WIDTH) as usize]));
+    let _ = world.set(player, Fov::new(BitGrid::new(MAP_WIDTH as usize, MAP_HEIGHT as usize, false)));
+    let _ = world.set(player, SpatialMemory::new(BitGrid::new(MAP_WIDTH as usize, MAP_HEIGHT as usize, false)));
     let _ = world.set(player, SightRange::new(5));
+    let _ = world.set(player, Keyring::new(0));
+    player
+}
 
-    let _ = world.set(monster, Velocity::new(0,0));
-    let _ = world.set(monster, Position::new(10, 10));
-    let _ = world.set(monster, Sprite::new('m'));
-    let _ = world.set(monster, Health::new(2, 5));
-    let _ = world.set(monster, TakeDamage::new());
-    let _ = world.set(monster, Name::new("Gorok".to_string()));
+/// The game's entities as the dungeon sees them.
+struct GameEntities;
+
+impl dungeon::Entities for GameEntities {
+    fn map_size(&self) -> (usize, usize) {
+        (MAP_WIDTH as usize, MAP_HEIGHT as usize)
+    }
+
+    fn spawn_level(&self, world: &mut Ecs, cave: &mapgen::MapInfo, depth: usize) {
+        spawn_level(world, cave, depth);
+    }
+
+    fn spawn_generated(&self, world: &mut Ecs, cave: &mapgen::MapInfo, rng: &mut XorShiftRng) {
+        spawn_wanderer(world, cave, rng);
+    }
+
+    fn player(&self, world: &Ecs) -> EntityId {
+        get_player(world)
+    }
+
+    fn store(&self, world: &Ecs, id: EntityId) -> Vec<serde_json::Value> {
+        entity_to_json(world, id)
+    }
+
+    fn restore(&self, world: &mut Ecs, components: &[serde_json::Value]) {
+        entity_from_json(world, components);
+    }
 
-    // let walls = vec![(1, 3), (2, 3), (3, 3), (4, 3), (5, 3)];
-    for point in walls {
-        let rock = world.create_entity();
-        let (x, y) = point;
-        let _ = world.set(rock, Position { x: x, y: y });
-        let _ = world.set(rock, Blocking {});
-        let _ = world.set(rock, Name::new("rock".to_string()));
-        let _ = world.set(rock, Sprite { glyph: '#' });
-        let _ = world.set(rock, BlockSight{} );
-        let _ = world.set(rock, Static {});
+    fn player_position(&self, world: &Ecs) -> (usize, usize) {
+        let pos = world.get::<Position>(get_player(world)).unwrap();
+        (pos.x as usize, pos.y as usize)
     }
 
-    calculate_fov(&mut world);
+    fn player_memory(&self, world: &Ecs) -> BitGrid {
+        world.get::<SpatialMemory>(get_player(world)).unwrap().memory
+    }
+
+    fn place_player(&self, world: &mut Ecs, p: (usize, usize), memory: BitGrid) {
+        let player = get_player(world);
+        let _ = world.set(player, Position::new(p.0 as i32, p.1 as i32));
+        let _ = world.set(player, SpatialMemory::new(memory));
+    }
+}
+
+/// A floor cell other than the start, the exit and the cells of spawns,
+/// `None` if the map has none.
+fn random_floor<R: Rng>(cave: &mapgen::MapInfo, rng: &mut R) -> Option<(usize, usize)> {
+    let floor: Vec<(usize, usize)> = cave
+        .walls
+        .enumerate()
+        .filter(|&(p, &c)| {
+            c == '.' && p != cave.start && p != cave.end && !cave.spawns.iter().any(|s| s.pos == p)
+        })
+        .map(|(p, _)| p)
+        .collect();
+    rng.choose(&floor).cloned()
+}
+
+fn arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1).cloned())
+}
+
+fn main() {
+    let mut con = RootConsole::initializer()
+        .font("arial10x10.png", FontLayout::Tcod)
+        .font_type(FontType::Greyscale)
+        .size(MAP_WIDTH, MAP_HEIGHT)
+        .title("ecs test")
+        .init();
+
+    let mut world = Ecs::new();
+    let seed = match arg_value("--seed") {
+        Some(seed) => seed.parse().expect("--seed takes an unsigned number"),
+        None => rand::random(),
+    };
+
+    let generator_name = arg_value("--generator").unwrap_or_else(|| "cave".to_string());
+    let mut generator = mapgen::generator_by_name(&generator_name).unwrap_or_else(|e| panic!("{}", e));
+
+    println!("Generating {} levels, seed: {}", generator.name(), seed);
+
+    let mut dungeon = Dungeon::new(seed, generator.name());
+    match arg_value("--level") {
+        Some(path) => {
+            let level = mapgen::load_level(std::path::Path::new(&path))
+                .unwrap_or_else(|e| panic!("{}", e));
+            if level.walls.width > MAP_WIDTH as usize || level.walls.height > MAP_HEIGHT as usize {
+                panic!("{}: levels can be at most {}x{}", path, MAP_WIDTH, MAP_HEIGHT);
+            }
+            dungeon::add_level(&mut world, &mut dungeon, &GameEntities, &level);
+        }
+        None => dungeon::generate_level(&mut world, &mut dungeon, &*generator, &GameEntities),
+    }
+
+    let (start_x, start_y) = dungeon.current().start;
+    spawn_player(&mut world, start_x as i32, start_y as i32);
+    let mut ai = AiContext::new(seed);
+
+    calculate_fov(&mut world, dungeon.map());
 
     while !con.window_closed(){
         con.clear();
 
         //let start = PreciseTime::now();
-        render(&world, &mut con);
+        render(&world, dungeon.map(), &mut con);
         //let end = PreciseTime::now();
         //println!("{} seconds for whatever you did.", start.to(end));
 
         con.flush();
-        player = get_player(&world);
+        let player = get_player(&world);
         let keypress = con.wait_for_keypress(true);
+        let mut travel = None;
 
         if keypress.pressed {
             match keypress {
@@ -543,20 +1268,348 @@ fn main() {
                     let v = world.get::<Velocity>(player).unwrap();
                     let _ = world.set(player, Velocity {x : v.x+1, y: v.y});
                 },
+                Key { printable: '>', .. } => {
+                    dungeon::descend(&mut world, &mut dungeon, &*generator, &GameEntities);
+                },
+                Key { printable: '<', .. } => {
+                    dungeon::ascend(&mut world, &mut dungeon, &*generator, &GameEntities);
+                },
+                Key { printable: 'c', .. } => {
+                    close_doors(&mut world);
+                },
+                Key { printable: 'x', .. } => {
+                    travel = Some(Travel::Explore);
+                },
+                Key { printable: 't', .. } => {
+                    travel = Some(Travel::Exit);
+                },
                 Key { code: F5, .. } => {
-                    save(&world);
+                    save(&world, &dungeon);
                 },
                 Key { code: F9, .. } => {
-                    load(&mut world);
+                    load(&mut world, &mut dungeon);
+                    // Deeper levels of the saved game come from its own generator.
+                    if dungeon.generator != generator.name() {
+                        match mapgen::generator_by_name(&dungeon.generator) {
+                            Ok(saved) => generator = saved,
+                            Err(e) => println!("{}, new levels come from {}", e, generator.name()),
+                        }
+                    }
                 },
                 _ => {}
             }
         }
 
-        move_or_attack(&mut world);
+        let alive = match travel {
+            Some(travel) => auto_travel(&mut world, &mut dungeon, &*generator, &mut con, &mut ai, travel),
+            None => play_turn(&mut world, &mut dungeon, &*generator, &mut ai),
+        };
+        if !alive {
+            break;
+        }
+
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mapgen::parse_level;
+
+    fn corridor() -> (Ecs, TileMap, EntityId) {
+        let level = parse_level("corridor", "---\n#######\n#@...>#\n#######\n").unwrap();
+        let mut world = Ecs::new();
+        let player = spawn_player(&mut world, 1, 1);
+        (world, TileMap::from_map_info(&level), player)
+    }
+
+    fn step_right(world: &mut Ecs, tiles: &TileMap, player: EntityId) -> Position {
+        let _ = world.set(player, Velocity::new(1, 0));
+        let moved = move_or_attack(world, tiles);
+        enter_terrain(world, tiles, &moved);
+        calculate_fov(world, tiles);
+        world.get(player).unwrap()
+    }
+
+    fn terrain_corridor() -> (Ecs, TileMap, EntityId) {
+        let level = parse_level("terrain", "---\n#########\n#@~.=.:>#\n#########\n").unwrap();
+        let mut world = Ecs::new();
+        let player = spawn_player(&mut world, 1, 1);
+        (world, TileMap::from_map_info(&level), player)
+    }
+
+    fn door_at(world: &Ecs, x: i32, y: i32) -> Door {
+        let mut doors = vec![];
+        world.collect_with(&component_filter!(Door, Position), &mut doors);
+        let id = doors
+            .into_iter()
+            .find(|id| world.get::<Position>(*id).unwrap() == Position::new(x, y))
+            .unwrap();
+        world.get(id).unwrap()
+    }
+
+    #[test]
+    fn test_random_floor() {
+        let mut rng = mapgen::seeded_rng(1);
+        let full = parse_level("full", "---\n#####\n#@>.#\n#####\n").unwrap();
+        assert_eq!(random_floor(&full, &mut rng), Some((3, 1)));
+
+        let taken = parse_level("taken", "m = monster Gorok\n---\n#####\n#@>m#\n#####\n").unwrap();
+        assert_eq!(random_floor(&taken, &mut rng), None);
+
+        let empty = parse_level("empty", "---\n####\n#@>#\n####\n").unwrap();
+        assert_eq!(random_floor(&empty, &mut rng), None);
+        let mut world = Ecs::new();
+        spawn_wanderer(&mut world, &empty, &mut rng);
+        assert_eq!(world.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_bump_opens_door() {
+        let (mut world, tiles, player) = corridor();
+        spawn_door(&mut world, 2, 1, DoorState::Closed);
+        calculate_fov(&mut world, &tiles);
+        assert!(!is_in_fov(&world.get::<Fov>(player).unwrap().fov, 3, 1));
+
+        assert_eq!(step_right(&mut world, &tiles, player), Position::new(1, 1));
+        assert_eq!(door_at(&world, 2, 1).state, DoorState::Open);
+        assert!(is_in_fov(&world.get::<Fov>(player).unwrap().fov, 3, 1));
+
+        assert_eq!(step_right(&mut world, &tiles, player), Position::new(2, 1));
+
+        step_right(&mut world, &tiles, player);
+        let _ = world.set(player, Velocity::new(0, 0));
+        close_doors(&mut world);
+        assert_eq!(door_at(&world, 2, 1).state, DoorState::Closed);
+    }
+
+    #[test]
+    fn test_locked_door_needs_key() {
+        let (mut world, tiles, player) = corridor();
+        let key = world.create_entity();
+        let _ = world.set(key, Position::new(2, 1));
+        let _ = world.set(key, DoorKey {});
+        spawn_door(&mut world, 3, 1, DoorState::Locked);
+
+        assert_eq!(step_right(&mut world, &tiles, player), Position::new(2, 1));
+        assert_eq!(world.get::<Keyring>(player).unwrap().keys, 1);
+        assert!(!world.exists(key));
+
+        step_right(&mut world, &tiles, player);
+        assert_eq!(door_at(&world, 3, 1).state, DoorState::Open);
+        assert_eq!(world.get::<Keyring>(player).unwrap().keys, 0);
+    }
+
+    #[test]
+    fn test_locked_door_without_key() {
+        let (mut world, tiles, player) = corridor();
+        spawn_door(&mut world, 2, 1, DoorState::Locked);
+
+        assert_eq!(step_right(&mut world, &tiles, player), Position::new(1, 1));
+        assert_eq!(door_at(&world, 2, 1).state, DoorState::Locked);
+    }
+
+    #[test]
+    fn test_door_survives_save() {
+        let mut world = Ecs::new();
+        let door = spawn_door(&mut world, 4, 2, DoorState::Locked);
+
+        let json = entity_to_json(&world, door);
+        let _ = world.destroy_entity(door);
+        let door = entity_from_json(&mut world, &json);
+
+        assert_eq!(world.get::<Door>(door).unwrap().state, DoorState::Locked);
+        assert!(world.has::<Blocking>(door).unwrap());
+        assert!(world.has::<BlockSight>(door).unwrap());
+    }
+
+    #[test]
+    fn test_deep_water_slows() {
+        let (mut world, tiles, player) = terrain_corridor();
+
+        assert_eq!(step_right(&mut world, &tiles, player), Position::new(2, 1));
+        assert_eq!(step_right(&mut world, &tiles, player), Position::new(2, 1));
+        assert_eq!(step_right(&mut world, &tiles, player), Position::new(2, 1));
+        assert_eq!(step_right(&mut world, &tiles, player), Position::new(3, 1));
+    }
+
+    #[test]
+    fn test_lava_burns() {
+        let (mut world, tiles, player) = terrain_corridor();
+        let _ = world.set(player, Position::new(3, 1));
+
+        step_right(&mut world, &tiles, player);
         take_dmg(&mut world);
-        calculate_fov(&mut world);
+        assert_eq!(world.get::<Health>(player).unwrap().hp, 10 - LAVA_DAMAGE);
+
+        // Standing still in it does not burn again.
+        let moved = move_or_attack(&mut world, &tiles);
+        assert!(moved.is_empty());
+    }
+
+    #[test]
+    fn test_chasm() {
+        let (mut world, tiles, player) = terrain_corridor();
+        let monster = spawn_monster(&mut world, "Gorok", 'm', 6, 1);
+        assert!(!enter_terrain(&mut world, &tiles, &[monster]));
+        assert!(!world.exists(monster));
+
+        let _ = world.set(player, Position::new(5, 1));
+        let _ = world.set(player, Velocity::new(1, 0));
+        let moved = move_or_attack(&mut world, &tiles);
+        assert!(enter_terrain(&mut world, &tiles, &moved));
+    }
+
+    #[test]
+    fn test_memory_survives_save() {
+        let (mut world, tiles, player) = corridor();
+        calculate_fov(&mut world, &tiles);
+        let memory = world.get::<SpatialMemory>(player).unwrap().memory;
+        assert!(memory.count_ones() > 0);
+
+        let json = entity_to_json(&world, player);
+        let _ = world.destroy_entity(player);
+        let player = entity_from_json(&mut world, &json);
+
+        assert_eq!(world.get::<SpatialMemory>(player).unwrap().memory, memory);
+        assert!(serde_json::to_string(&json).unwrap().len() < 1000);
+    }
+
+    fn travel_to_exit(world: &mut Ecs, tiles: &TileMap) -> usize {
+        let exit = world.create_entity();
+        let _ = world.set(exit, Position::new(5, 1));
+        let _ = world.set(exit, Exit {});
+        let goals = travel_goals(world, tiles, Travel::Exit);
+        let map = DijkstraMap::new(travel_costs(world, tiles, player_keys(world)), &goals);
+
+        let mut turns = 0;
+        while travel_step(world, &map) {
+            move_or_attack(world, tiles);
+            turns += 1;
+            assert!(turns < 10);
+        }
+        turns
+    }
 
+    #[test]
+    fn test_travel_opens_doors() {
+        let (mut world, tiles, player) = corridor();
+        spawn_door(&mut world, 3, 1, DoorState::Closed);
+
+        // Four steps and one turn for opening the door.
+        assert_eq!(travel_to_exit(&mut world, &tiles), 5);
+        assert_eq!(world.get::<Position>(player).unwrap(), Position::new(5, 1));
+    }
+
+    #[test]
+    fn test_travel_needs_key() {
+        let (mut world, tiles, _player) = corridor();
+        spawn_door(&mut world, 3, 1, DoorState::Locked);
+        assert_eq!(travel_to_exit(&mut world, &tiles), 0);
+
+        let (mut world, tiles, player) = corridor();
+        spawn_door(&mut world, 3, 1, DoorState::Locked);
+        let _ = world.set(player, Keyring::new(1));
+        assert_eq!(travel_to_exit(&mut world, &tiles), 5);
+        assert_eq!(world.get::<Position>(player).unwrap(), Position::new(5, 1));
+    }
+
+    #[test]
+    fn test_explore_goals() {
+        let (mut world, tiles, _player) = corridor();
+        assert_eq!(travel_goals(&world, &tiles, Travel::Explore).len(), 5);
+
+        calculate_fov(&mut world, &tiles);
+        assert!(travel_goals(&world, &tiles, Travel::Explore).is_empty());
+    }
+
+    fn ai_turn(world: &mut Ecs, tiles: &TileMap, ai: &mut AiContext) {
+        monster_ai(world, tiles, ai);
+        move_or_attack(world, tiles);
+        take_dmg(world);
+        calculate_fov(world, tiles);
+    }
+
+    #[test]
+    fn test_monster_hunts_around_lava() {
+        let level = parse_level("lava", "---\n#######\n#....>#\n#@.=..#\n#.....#\n#######\n").unwrap();
+        let tiles = TileMap::from_map_info(&level);
+        let mut world = Ecs::new();
+        let player = spawn_player(&mut world, 1, 2);
+        let monster = spawn_monster(&mut world, "Gorok", 'm', 5, 2);
+        let mut ai = AiContext::new(1);
+        calculate_fov(&mut world, &tiles);
+
+        let mut turns = 0;
+        while world.get::<Health>(player).unwrap().hp == 10 {
+            ai_turn(&mut world, &tiles, &mut ai);
+            assert_ne!(world.get::<Position>(monster).unwrap(), Position::new(3, 2));
+            turns += 1;
+            assert!(turns < 10);
+        }
+        assert_eq!(world.get::<Ai>(monster).unwrap().state, AiState::Hunting);
+        assert_eq!(world.get::<Health>(player).unwrap().hp, 9);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_hurt_monster_flees() {
+        let level = parse_level("hall", "---\n##########\n#@......>#\n##########\n").unwrap();
+        let tiles = TileMap::from_map_info(&level);
+        let mut world = Ecs::new();
+        spawn_player(&mut world, 1, 1);
+        let monster = spawn_monster(&mut world, "Gorok", 'm', 4, 1);
+        let _ = world.set(monster, Health::new(1, 5));
+        let mut ai = AiContext::new(1);
+        calculate_fov(&mut world, &tiles);
+
+        ai_turn(&mut world, &tiles, &mut ai);
+        ai_turn(&mut world, &tiles, &mut ai);
+
+        assert_eq!(world.get::<Position>(monster).unwrap(), Position::new(6, 1));
+        assert_eq!(world.get::<Ai>(monster).unwrap().state, AiState::Fleeing);
+    }
+
+    #[test]
+    fn test_monster_wanders() {
+        let level = parse_level("cell", "---\n##########\n#@#.....>#\n##########\n").unwrap();
+        let tiles = TileMap::from_map_info(&level);
+        let mut world = Ecs::new();
+        spawn_player(&mut world, 1, 1);
+        let monster = spawn_monster(&mut world, "Gorok", 'm', 3, 1);
+        let mut ai = AiContext::new(3);
+        calculate_fov(&mut world, &tiles);
+
+        let mut seen = vec![];
+        for _ in 0..200 {
+            ai_turn(&mut world, &tiles, &mut ai);
+            let pos = world.get::<Position>(monster).unwrap();
+            assert!(tiles.is_walkable(pos.x, pos.y) && pos.x > 2);
+            if !seen.contains(&pos.x) {
+                seen.push(pos.x);
+            }
+        }
+        assert!(seen.len() > 3);
+    }
+
+    #[test]
+    fn test_monsters_do_not_fight_each_other() {
+        let (mut world, tiles, player) = corridor();
+        let first = spawn_monster(&mut world, "Gorok", 'm', 3, 1);
+        let second = spawn_monster(&mut world, "Gorok", 'm', 4, 1);
+        let mut ai = AiContext::new(1);
+        calculate_fov(&mut world, &tiles);
+
+        for _ in 0..3 {
+            ai_turn(&mut world, &tiles, &mut ai);
+        }
+        assert_eq!(world.get::<Health>(first).unwrap().hp, 2);
+        assert_eq!(world.get::<Health>(second).unwrap().hp, 2);
+        assert!(world.get::<Health>(player).unwrap().hp < 10);
+
+        let json = entity_to_json(&world, second);
+        let copy = entity_from_json(&mut world, &json);
+        assert_eq!(world.get::<Ai>(copy).unwrap(), world.get::<Ai>(second).unwrap());
+    }
+}
@@ -0,0 +1,279 @@
+use grid::{manhattan, BitGrid, NodeMap, Rect};
+use mapgen::{MapGenerator, MapInfo, CORRIDOR_COLOR, ROOM_COLORS, WALL_COLOR};
+
+use rand::{Rng, XorShiftRng};
+
+use pathfinding::astar;
+
+/// How rooms of sibling partitions are joined together.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Corridor {
+    /// One horizontal and one vertical leg between room centers.
+    LShaped,
+    /// Shortest route that prefers running through already open floor.
+    AStar,
+}
+
+/// Binary space partition rooms-and-corridors layout.
+#[derive(Clone, Debug, new)]
+pub struct BspGenerator {
+    /// Partitions are not split below this width or height.
+    pub min_leaf: usize,
+    /// Smallest width and height of a room inside a partition.
+    pub min_room: usize,
+    pub corridor: Corridor,
+}
+
+impl Default for BspGenerator {
+    fn default() -> BspGenerator {
+        BspGenerator::new(10, 4, Corridor::LShaped)
+    }
+}
+
+impl MapGenerator for BspGenerator {
+    fn name(&self) -> &str {
+        match self.corridor {
+            Corridor::LShaped => "bsp",
+            Corridor::AStar => "bsp-astar",
+        }
+    }
+
+    fn generate(&self, width: usize, height: usize, rng: &mut XorShiftRng) -> MapInfo {
+        generate_bsp(width, height, self, rng)
+    }
+}
+
+fn split<R: Rng>(area: Rect, settings: &BspGenerator, rng: &mut R) -> Option<(Rect, Rect)> {
+    let min = settings.min_leaf;
+    let can_split_w = area.w >= 2 * min;
+    let can_split_h = area.h >= 2 * min;
+
+    let vertical = match (can_split_w, can_split_h) {
+        (false, false) => return None,
+        (true, false) => true,
+        (false, true) => false,
+        (true, true) => {
+            if area.w * 4 > area.h * 5 {
+                true
+            } else if area.h * 4 > area.w * 5 {
+                false
+            } else {
+                rng.gen()
+            }
+        }
+    };
+
+    if vertical {
+        let at = rng.gen_range(min, area.w - min + 1);
+        Some((
+            Rect { w: at, ..area },
+            Rect { x: area.x + at, w: area.w - at, ..area },
+        ))
+    } else {
+        let at = rng.gen_range(min, area.h - min + 1);
+        Some((
+            Rect { h: at, ..area },
+            Rect { y: area.y + at, h: area.h - at, ..area },
+        ))
+    }
+}
+
+fn place_room<R: Rng>(leaf: Rect, settings: &BspGenerator, rng: &mut R) -> Rect {
+    // Leave a wall between the room and the leaf boundary on every side.
+    let max_w = leaf.w.saturating_sub(2).max(1);
+    let max_h = leaf.h.saturating_sub(2).max(1);
+    let w = rng.gen_range(settings.min_room.min(max_w), max_w + 1);
+    let h = rng.gen_range(settings.min_room.min(max_h), max_h + 1);
+    let x = leaf.x + if leaf.w > w + 1 { rng.gen_range(1, leaf.w - w) } else { 0 };
+    let y = leaf.y + if leaf.h > h + 1 { rng.gen_range(1, leaf.h - h) } else { 0 };
+
+    Rect::new(x, y, w, h)
+}
+
+/// Splits `area` recursively, carves a room in every leaf and returns the
+/// rooms in tree order. Sibling subtrees are joined with one corridor.
+fn build<R: Rng>(
+    area: Rect,
+    settings: &BspGenerator,
+    nm: &mut NodeMap<i32>,
+    rng: &mut R,
+) -> Vec<Rect> {
+    match split(area, settings, rng) {
+        None => {
+            let room = place_room(area, settings, rng);
+            for p in room.points() {
+                nm.set(&p, 0);
+            }
+            vec![room]
+        }
+        Some((a, b)) => {
+            let mut rooms = build(a, settings, nm, rng);
+            let other = build(b, settings, nm, rng);
+
+            let from = rooms[rng.gen_range(0, rooms.len())].center();
+            let to = other[rng.gen_range(0, other.len())].center();
+            match settings.corridor {
+                Corridor::LShaped => carve_l(nm, from, to, rng.gen()),
+                Corridor::AStar => carve_astar(nm, from, to),
+            }
+
+            rooms.extend(other);
+            rooms
+        }
+    }
+}
+
+fn carve_l(nm: &mut NodeMap<i32>, from: (usize, usize), to: (usize, usize), horizontal_first: bool) {
+    let corner = if horizontal_first {
+        (to.0, from.1)
+    } else {
+        (from.0, to.1)
+    };
+
+    for &(a, b) in &[(from, corner), (corner, to)] {
+        for x in a.0.min(b.0)..a.0.max(b.0) + 1 {
+            for y in a.1.min(b.1)..a.1.max(b.1) + 1 {
+                nm.set(&(x, y), 0);
+            }
+        }
+    }
+}
+
+fn carve_astar(nm: &mut NodeMap<i32>, from: (usize, usize), to: (usize, usize)) {
+    let width = nm.width;
+    let height = nm.height;
+
+    let path = {
+        let map = &*nm;
+        astar(
+            &from,
+            |&(x, y)| {
+                let mut next = vec![];
+                if x > 1 {
+                    next.push((x - 1, y));
+                }
+                if y > 1 {
+                    next.push((x, y - 1));
+                }
+                if x + 2 < width {
+                    next.push((x + 1, y));
+                }
+                if y + 2 < height {
+                    next.push((x, y + 1));
+                }
+                // Digging through rock costs more than walking open floor,
+                // so corridors merge instead of running side by side.
+                next.into_iter()
+                    .map(|p| {
+                        let cost = if map.get(&p) == 0 { 1 } else { 3 };
+                        (p, cost)
+                    })
+                    .collect::<Vec<_>>()
+            },
+            |&(x, y)| manhattan((x as i32, y as i32), (to.0 as i32, to.1 as i32)) as usize,
+            |p| *p == to,
+        )
+    };
+
+    if let Some((points, _)) = path {
+        for p in points {
+            nm.set(&p, 0);
+        }
+    }
+}
+
+pub fn generate_bsp<R: Rng>(
+    width: usize,
+    height: usize,
+    settings: &BspGenerator,
+    rng: &mut R,
+) -> MapInfo {
+    let mut nm = NodeMap::new(width, height, 1);
+
+    // The outermost ring stays solid rock.
+    let area = Rect::new(1, 1, width - 2, height - 2);
+    let rooms = build(area, settings, &mut nm, rng);
+
+    let mut colormat = NodeMap::new(width, height, WALL_COLOR);
+    for (i, room) in rooms.iter().enumerate() {
+        for p in room.points() {
+            colormat.set(&p, ROOM_COLORS[i % ROOM_COLORS.len()]);
+        }
+    }
+
+    let mat = nm.map(|&cell| if cell == 1 { '#' } else { '.' });
+    let colormat = colormat.zip_with(&mat, |&color, &c| {
+        if c == '.' && color == WALL_COLOR {
+            CORRIDOR_COLOR
+        } else {
+            color
+        }
+    });
+
+    MapInfo {
+        walls: mat,
+        colors: colormat,
+        blocked: BitGrid::new(width, height, false),
+        visible: BitGrid::new(width, height, true),
+        start: rooms[0].center(),
+        end: rooms[rooms.len() - 1].center(),
+        spawns: vec![],
+        regions: rooms.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use grid::distance_field;
+    use mapgen::seeded_rng;
+
+    fn reachable_floor(walls: &NodeMap<char>, start: (usize, usize)) -> usize {
+        distance_field(walls, &[start], |&c| c != '#').count_where(Option::is_some)
+    }
+
+    #[test]
+    fn test_bsp_connected() {
+        for seed in 0..20 {
+            let map = generate_bsp(80, 50, &BspGenerator::default(), &mut seeded_rng(seed));
+            map.walls.print();
+
+            assert_eq!(reachable_floor(&map.walls, map.start), map.walls.count_where(|&c| c != '#'));
+            assert!(map.walls.get(&map.end) != '#');
+        }
+    }
+
+    #[test]
+    fn test_bsp_astar_connected() {
+        let settings = BspGenerator::new(8, 3, Corridor::AStar);
+        for seed in 0..20 {
+            let map = generate_bsp(60, 40, &settings, &mut seeded_rng(seed));
+
+            assert_eq!(reachable_floor(&map.walls, map.start), map.walls.count_where(|&c| c != '#'));
+        }
+    }
+
+    #[test]
+    fn test_bsp_solid_edges() {
+        let map = generate_bsp(50, 30, &BspGenerator::default(), &mut seeded_rng(5));
+
+        for x in 0..50 {
+            assert_eq!(map.walls.get(&(x, 0)), '#');
+            assert_eq!(map.walls.get(&(x, 29)), '#');
+        }
+        for y in 0..30 {
+            assert_eq!(map.walls.get(&(0, y)), '#');
+            assert_eq!(map.walls.get(&(49, y)), '#');
+        }
+    }
+
+    #[test]
+    fn test_bsp_same_seed() {
+        let settings = BspGenerator::default();
+        let a = generate_bsp(80, 50, &settings, &mut seeded_rng(11));
+        let b = generate_bsp(80, 50, &settings, &mut seeded_rng(11));
+
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,533 @@
+use std::collections::{HashMap, VecDeque};
+
+use grid::NodeMap;
+use mapgen::SOLID;
+
+use rand::Rng;
+
+/// The route a tunnel takes between two regions.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum TunnelStyle {
+    /// The shortest route through the rock.
+    Straight,
+    /// A random walk that drifts towards the other region.
+    Winding,
+}
+
+/// How `connect_rooms` digs the tunnels between regions.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Tunnels {
+    /// Tunnels are dug this many cells wide.
+    pub width: usize,
+    pub style: TunnelStyle,
+    /// Chance from 0 to 1 to also dig a tunnel between neighbouring regions
+    /// that are already connected, giving the map loops.
+    pub loop_chance: f32,
+    /// Chance from 0 to 1 for a door where a tunnel opens into a region.
+    /// Only one cell wide tunnels get doors.
+    #[serde(default)]
+    pub door_chance: f32,
+}
+
+impl Default for Tunnels {
+    fn default() -> Tunnels {
+        Tunnels {
+            width: 1,
+            style: TunnelStyle::Straight,
+            loop_chance: 0.0,
+            door_chance: 0.0,
+        }
+    }
+}
+
+/// Union-find over region indices, for Kruskal's algorithm.
+struct DisjointSet {
+    parent: Vec<usize>,
+    rank: Vec<u8>,
+}
+
+impl DisjointSet {
+    fn new(size: usize) -> DisjointSet {
+        DisjointSet {
+            parent: (0..size).collect(),
+            rank: vec![0; size],
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            // Path halving keeps the trees flat without recursion.
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    /// Joins the sets of `a` and `b`, false if they already were one.
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        if self.rank[a] < self.rank[b] {
+            self.parent[a] = b;
+        } else if self.rank[a] > self.rank[b] {
+            self.parent[b] = a;
+        } else {
+            self.parent[b] = a;
+            self.rank[a] += 1;
+        }
+        true
+    }
+}
+
+/// The cheapest known tunnel between two regions: the rock cells `a` and
+/// `b` touch, `a` was reached from the first region and `b` from the
+/// second.
+#[derive(Copy, Clone, Debug)]
+struct Link {
+    regions: (i32, i32),
+    cost: usize,
+    a: (usize, usize),
+    b: (usize, usize),
+}
+
+fn is_edge(p: (usize, usize), width: usize, height: usize) -> bool {
+    p.0 == 0 || p.1 == 0 || p.0 + 1 == width || p.1 + 1 == height
+}
+
+/// The nearest cell to `p` that is not on the outermost ring of the map.
+fn inside(p: (usize, usize), width: usize, height: usize) -> (usize, usize) {
+    (p.0.max(1).min(width - 2), p.1.max(1).min(height - 2))
+}
+
+/// Walks from `from` to `to`, mostly stepping closer but now and then in
+/// a random direction. Stays off the outermost ring of the map: ends on
+/// the ring are left through their neighbour inside it.
+pub fn winding_path<R: Rng>(
+    from: (usize, usize),
+    to: (usize, usize),
+    width: usize,
+    height: usize,
+    rng: &mut R,
+) -> Vec<(usize, usize)> {
+    let target = inside(to, width, height);
+    let mut p = inside(from, width, height);
+    let mut path = vec![from];
+    if p != from {
+        path.push(p);
+    }
+
+    while p != target {
+        let dx = (target.0 as i32 - p.0 as i32).signum();
+        let dy = (target.1 as i32 - p.1 as i32).signum();
+        let step = if rng.gen_range(0, 3) == 0 {
+            [(1, 0), (-1, 0), (0, 1), (0, -1)][rng.gen_range(0, 4)]
+        } else if dx != 0 && (dy == 0 || rng.gen()) {
+            (dx, 0)
+        } else {
+            (0, dy)
+        };
+
+        let next = ((p.0 as i32 + step.0) as usize, (p.1 as i32 + step.1) as usize);
+        if !is_edge(next, width, height) {
+            p = next;
+            path.push(p);
+        }
+    }
+    if to != target {
+        path.push(to);
+    }
+    path
+}
+
+/// Turns the rock in a `size` x `size` square around `p` into `region`.
+fn dig(connected: &mut NodeMap<i32>, p: (usize, usize), size: usize, region: i32) {
+    let (width, height) = (connected.width, connected.height);
+    let low = (size.max(1) - 1) / 2;
+
+    for j in 0..size.max(1) {
+        for i in 0..size.max(1) {
+            if p.0 + i < low || p.1 + j < low {
+                continue;
+            }
+            let q = (p.0 + i - low, p.1 + j - low);
+            if connected.in_bounds(&q) && !is_edge(q, width, height) && connected.get(&q) == 1 {
+                connected.set(&q, region);
+            }
+        }
+    }
+}
+
+/// Connects the regions numbered `2..2 + number_rooms` by `fill_map` with
+/// tunnels through rock (1). Tunnel cells take the lower number of the two
+/// regions they join, the outermost ring of the map and `SOLID` rock are
+/// never dug. Returns the connected map and the mouths of the tunnels, the
+/// first and last cell dug for each of them.
+///
+/// Regions touching the outermost ring are dug out from their other cells.
+/// A region lying entirely on the ring, with no rock inside the ring next
+/// to it, cannot be reached and stays unconnected; the generators keep the
+/// ring rock, so their maps never have one.
+///
+/// All regions grow into the rock at once, one cell per step. Where two of
+/// them meet is the shortest tunnel between the pair, and a minimum
+/// spanning tree over those pairs picks the tunnels to dig. Every cell is
+/// visited once, so this stays fast for large maps with many regions.
+pub fn connect_rooms<R: Rng>(
+    nm: &NodeMap<i32>,
+    number_rooms: usize,
+    tunnels: &Tunnels,
+    rng: &mut R,
+) -> (NodeMap<i32>, Vec<(usize, usize)>) {
+    let (width, height) = (nm.width, nm.height);
+    let mut connected = nm.clone();
+    let mut mouths = vec![];
+    if number_rooms < 2 {
+        return (connected, mouths);
+    }
+
+    let mut owner: NodeMap<i32> = nm.clone();
+    let mut distance = NodeMap::new(width, height, 0usize);
+    let mut parent = NodeMap::new(width, height, (0, 0));
+    let mut queue = VecDeque::new();
+
+    queue.extend(nm.enumerate().filter(|&(_, &c)| c > 1).map(|(p, _)| p));
+
+    let mut links: HashMap<(i32, i32), Link> = HashMap::new();
+
+    while let Some(p) = queue.pop_front() {
+        let region = owner.get(&p);
+
+        for n in owner.neighbours4(&p) {
+            let other = owner.get(&n);
+            if other == region || other == SOLID {
+                continue;
+            }
+            if other != 1 {
+                let cost = distance.get(&p) + distance.get(&n);
+                let (key, link) = if region < other {
+                    ((region, other), Link { regions: (region, other), cost, a: p, b: n })
+                } else {
+                    ((other, region), Link { regions: (other, region), cost, a: n, b: p })
+                };
+                match links.get(&key) {
+                    Some(known) if known.cost <= cost => (),
+                    _ => {
+                        links.insert(key, link);
+                    }
+                }
+                continue;
+            }
+
+            if !is_edge(n, width, height) {
+                owner.set(&n, region);
+                distance.set(&n, distance.get(&p) + 1);
+                parent.set(&n, p);
+                queue.push_back(n);
+            }
+        }
+    }
+
+    let mut links: Vec<Link> = links.into_values().collect();
+    // Hash map order is arbitrary, shuffle from a known order so ties are
+    // broken by the map's rng and the result stays reproducible.
+    links.sort_by_key(|l| l.regions);
+    rng.shuffle(&mut links);
+    links.sort_by_key(|l| l.cost);
+
+    let mut regions = DisjointSet::new(number_rooms);
+    let mut joined = 1;
+
+    for link in links {
+        let (r1, r2) = link.regions;
+        if !regions.union((r1 - 2) as usize, (r2 - 2) as usize) {
+            // Only roll for loops when asked to, so maps without them come
+            // out the same as before loops existed.
+            let looped = tunnels.loop_chance > 0.0 && rng.gen::<f32>() < tunnels.loop_chance;
+            if !looped {
+                continue;
+            }
+        } else {
+            joined += 1;
+        }
+
+        // The shortest route is followed back from the meeting point to
+        // both regions, then put in order from the first to the second.
+        let mut ends = vec![];
+        let mut halves = vec![];
+        for &start in &[link.a, link.b] {
+            let mut half = vec![];
+            let mut p = start;
+            while nm.get(&p) == 1 {
+                half.push(p);
+                p = parent.get(&p);
+            }
+            ends.push(p);
+            halves.push(half);
+        }
+        halves[0].reverse();
+        let mut route: Vec<(usize, usize)> = halves.concat();
+
+        if tunnels.style == TunnelStyle::Winding {
+            route = winding_path(ends[0], ends[1], width, height, rng);
+        }
+        {
+            let mut dug = route.iter().filter(|p| nm.get(p) == 1);
+            mouths.extend(dug.next());
+            mouths.extend(dug.next_back());
+        }
+        for p in route {
+            dig(&mut connected, p, tunnels.width, r1);
+        }
+
+        if joined == number_rooms && tunnels.loop_chance <= 0.0 {
+            break;
+        }
+    }
+    (connected, mouths)
+}
+
+/// Picks the tunnel mouths of a connected map that get a door. A door needs
+/// rock on two opposite sides and floor on the other two, so it really
+/// closes the passage.
+pub fn tunnel_doors<R: Rng>(
+    connected: &NodeMap<i32>,
+    mouths: &[(usize, usize)],
+    tunnels: &Tunnels,
+    rng: &mut R,
+) -> Vec<(usize, usize)> {
+    let mut doors = vec![];
+    if tunnels.door_chance <= 0.0 || tunnels.width > 1 {
+        return doors;
+    }
+
+    let (width, height) = (connected.width, connected.height);
+    let rock = |x: usize, y: usize| connected.get(&(x, y)) == 1 || connected.get(&(x, y)) == SOLID;
+
+    for &(x, y) in mouths {
+        if is_edge((x, y), width, height) || doors.contains(&(x, y)) {
+            continue;
+        }
+        let across = rock(x - 1, y) && rock(x + 1, y) && !rock(x, y - 1) && !rock(x, y + 1);
+        let along = rock(x, y - 1) && rock(x, y + 1) && !rock(x - 1, y) && !rock(x + 1, y);
+        // Two doors side by side would wall each other in.
+        let neighbour_door = doors.iter().any(|&(dx, dy): &(usize, usize)| {
+            (dx as i32 - x as i32).abs() + (dy as i32 - y as i32).abs() == 1
+        });
+
+        if (across || along) && !neighbour_door && rng.gen::<f32>() < tunnels.door_chance {
+            doors.push((x, y));
+        }
+    }
+    doors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use serde_json;
+
+    use mapgen::{fill_edges_with, fill_map, new_binary_nodemap, seeded_rng};
+
+    fn region_count(nm: &NodeMap<i32>) -> usize {
+        let mut floor = nm.map(|&c| if c == 1 { 1 } else { 0 });
+        fill_map(&mut floor)
+    }
+
+    #[test]
+    fn test_connect_rooms() {
+        let mut nm = NodeMap::new(10, 10, 1);
+        let room1 = &[(1, 1), (1, 2), (2, 2), (2, 1)];
+        let room2 = &[(8, 8), (9, 9), (8, 9), (9, 8)];
+        let room3 = &[(3, 5), (3, 6), (4, 5), (4, 6)];
+
+        for point in room1 {
+            nm.set(point, 2);
+        }
+
+        for point in room2 {
+            nm.set(point, 3);
+        }
+
+        for point in room3 {
+            nm.set(point, 4);
+        }
+
+        let (connected, _) = connect_rooms(&nm, 3, &Tunnels::default(), &mut seeded_rng(4));
+
+        connected.print();
+        // Room 2 lies on the edge, it is reached through its inner cell.
+        assert_eq!(region_count(&connected), 1);
+    }
+
+    #[test]
+    fn test_tunnels_stay_off_edge() {
+        let mut nm = NodeMap::new(10, 10, 1);
+        let rooms = [
+            [(1, 1), (1, 2), (2, 2), (2, 1)],
+            [(7, 7), (8, 8), (7, 8), (8, 7)],
+            [(3, 5), (3, 6), (4, 5), (4, 6)],
+        ];
+        for (i, room) in rooms.iter().enumerate() {
+            for point in room {
+                nm.set(point, i as i32 + 2);
+            }
+        }
+
+        let (connected, mouths) = connect_rooms(&nm, 3, &Tunnels::default(), &mut seeded_rng(4));
+
+        assert_eq!(region_count(&connected), 1);
+        assert_eq!(mouths.len(), 4);
+        for mouth in mouths {
+            assert_eq!(nm.get(&mouth), 1);
+            assert!(connected.get(&mouth) != 1);
+        }
+        for i in 0..10 {
+            assert_eq!(connected.get(&(i, 0)), 1);
+            assert_eq!(connected.get(&(0, i)), 1);
+            assert_eq!(connected.get(&(i, 9)), 1);
+            assert_eq!(connected.get(&(9, i)), 1);
+        }
+    }
+
+    #[test]
+    fn test_winding_tunnel_from_edge() {
+        let mut nm = NodeMap::new(8, 8, 1);
+        nm.set(&(5, 3), 2);
+        nm.set(&(6, 3), 2);
+        nm.set(&(0, 3), 3);
+        let tunnels = Tunnels {
+            style: TunnelStyle::Winding,
+            ..Tunnels::default()
+        };
+
+        for seed in 0..20 {
+            let (connected, _) = connect_rooms(&nm, 3, &tunnels, &mut seeded_rng(seed));
+
+            assert_eq!(region_count(&connected), 1);
+            for i in 0..8 {
+                if i != 3 {
+                    assert_eq!(connected.get(&(0, i)), 1);
+                }
+                assert_eq!(connected.get(&(i, 0)), 1);
+                assert_eq!(connected.get(&(i, 7)), 1);
+                assert_eq!(connected.get(&(7, i)), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_region_only_on_edge() {
+        // A single floor cell in the corner has no rock it could be dug
+        // out through, it stays on its own.
+        let mut nm = NodeMap::new(6, 6, 1);
+        nm.set(&(0, 0), 2);
+        for p in &[(2, 2), (3, 2), (2, 3), (3, 3)] {
+            nm.set(p, 3);
+        }
+        for p in &[(4, 5), (4, 4)] {
+            nm.set(p, 4);
+        }
+
+        let (connected, _) = connect_rooms(&nm, 3, &Tunnels::default(), &mut seeded_rng(4));
+
+        assert_eq!(region_count(&connected), 2);
+        assert_eq!(connected.get(&(4, 5)), 4);
+        assert!(connected.get(&(1, 0)) == 1 && connected.get(&(0, 1)) == 1);
+    }
+
+    #[test]
+    fn test_disjoint_set() {
+        let mut set = DisjointSet::new(4);
+
+        assert!(set.union(0, 1));
+        assert!(set.union(2, 3));
+        assert!(!set.union(1, 0));
+        assert!(set.union(1, 3));
+        assert_eq!(set.find(0), set.find(2));
+    }
+
+    #[test]
+    fn test_connect_many_regions() {
+        let mut nm = new_binary_nodemap(500, 500, 55, &mut seeded_rng(9));
+        fill_edges_with(&mut nm, 1);
+        let regions = fill_map(&mut nm);
+        assert!(regions > 2000);
+
+        let now = Instant::now();
+        let (connected, _) = connect_rooms(&nm, regions, &Tunnels::default(), &mut seeded_rng(9));
+        println!("{} regions connected in {:?}", regions, now.elapsed());
+
+        assert_eq!(region_count(&connected), 1);
+    }
+
+    fn noise(seed: u64) -> (NodeMap<i32>, usize) {
+        let mut nm = new_binary_nodemap(80, 50, 60, &mut seeded_rng(seed));
+        fill_edges_with(&mut nm, 1);
+        let regions = fill_map(&mut nm);
+        (nm, regions)
+    }
+
+    #[test]
+    fn test_tunnel_styles() {
+        let (nm, regions) = noise(10);
+        let (straight, _) = connect_rooms(&nm, regions, &Tunnels::default(), &mut seeded_rng(1));
+
+        let settings = [
+            Tunnels { width: 3, ..Tunnels::default() },
+            Tunnels { style: TunnelStyle::Winding, ..Tunnels::default() },
+            Tunnels { loop_chance: 0.5, ..Tunnels::default() },
+        ];
+        for tunnels in settings.iter() {
+            let (connected, _) = connect_rooms(&nm, regions, tunnels, &mut seeded_rng(1));
+
+            assert_eq!(region_count(&connected), 1);
+            assert!(connected.count_where(|&c| c != 1) > straight.count_where(|&c| c != 1));
+            for i in 0..80 {
+                assert_eq!(connected.get(&(i, 0)), 1);
+                assert_eq!(connected.get(&(i, 49)), 1);
+            }
+            for i in 0..50 {
+                assert_eq!(connected.get(&(0, i)), 1);
+                assert_eq!(connected.get(&(79, i)), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tunnel_doors() {
+        let (nm, regions) = noise(11);
+        let tunnels = Tunnels {
+            door_chance: 1.0,
+            ..Tunnels::default()
+        };
+        let (connected, mouths) = connect_rooms(&nm, regions, &tunnels, &mut seeded_rng(2));
+        let doors = tunnel_doors(&connected, &mouths, &tunnels, &mut seeded_rng(2));
+
+        assert!(!doors.is_empty());
+        for &(x, y) in doors.iter() {
+            let rock = |x, y| connected.get(&(x, y)) == 1;
+            assert!(!rock(x, y));
+            assert!(rock(x - 1, y) && rock(x + 1, y) || rock(x, y - 1) && rock(x, y + 1));
+        }
+
+        let wide = Tunnels {
+            width: 2,
+            ..tunnels
+        };
+        assert!(tunnel_doors(&connected, &mouths, &wide, &mut seeded_rng(2)).is_empty());
+    }
+
+    #[test]
+    fn test_tunnels_from_json() {
+        let tunnels: Tunnels =
+            serde_json::from_str(r#"{"width": 2, "style": "Winding", "loop_chance": 0.1}"#).unwrap();
+
+        assert_eq!(tunnels.style, TunnelStyle::Winding);
+        assert_eq!(tunnels.width, 2);
+    }
+}
@@ -0,0 +1,143 @@
+use grid::NodeMap;
+use mapgen::{finish_map, MapGenerator, MapInfo, TerrainRules, Tunnels};
+
+use rand::{Rng, XorShiftRng};
+
+/// Drunkard's walk tunnels: random walkers dig through solid rock until
+/// enough of the map is open floor.
+#[derive(Clone, Debug, new)]
+pub struct DrunkardGenerator {
+    /// Walkers digging at the same time.
+    pub walkers: usize,
+    /// Percentage of the map that should end up as floor.
+    pub floor_target: usize,
+    /// Steps a walker takes before it is respawned on dug out floor.
+    pub lifetime: usize,
+}
+
+impl Default for DrunkardGenerator {
+    fn default() -> DrunkardGenerator {
+        DrunkardGenerator::new(4, 40, 200)
+    }
+}
+
+impl MapGenerator for DrunkardGenerator {
+    fn name(&self) -> &str {
+        "drunkard"
+    }
+
+    fn generate(&self, width: usize, height: usize, rng: &mut XorShiftRng) -> MapInfo {
+        generate_drunkard(width, height, self, rng)
+    }
+}
+
+struct Walker {
+    pos: (usize, usize),
+    age: usize,
+}
+
+fn step<R: Rng>(pos: (usize, usize), width: usize, height: usize, rng: &mut R) -> (usize, usize) {
+    let (x, y) = pos;
+    // Walkers stay off the outermost ring so the edges remain rock.
+    match rng.gen_range(0, 4) {
+        0 if x > 1 => (x - 1, y),
+        1 if x + 2 < width => (x + 1, y),
+        2 if y > 1 => (x, y - 1),
+        3 if y + 2 < height => (x, y + 1),
+        _ => pos,
+    }
+}
+
+fn random_floor<R: Rng>(nm: &NodeMap<i32>, rng: &mut R) -> (usize, usize) {
+    loop {
+        let p = (rng.gen_range(1, nm.width - 1), rng.gen_range(1, nm.height - 1));
+        if nm.get(&p) == 0 {
+            return p;
+        }
+    }
+}
+
+/// Digs a solid map open with random walkers, returns it with 1 = rock.
+fn dig<R: Rng>(width: usize, height: usize, settings: &DrunkardGenerator, rng: &mut R) -> NodeMap<i32> {
+    let mut nm = NodeMap::new(width, height, 1);
+
+    let interior = (width - 2) * (height - 2);
+    let target = (width * height * settings.floor_target / 100).min(interior);
+
+    let center = (width / 2, height / 2);
+    nm.set(&center, 0);
+    let mut open = 1;
+
+    let mut walkers: Vec<Walker> = (0..settings.walkers.max(1))
+        .map(|_| Walker { pos: center, age: 0 })
+        .collect();
+
+    while open < target {
+        for walker in walkers.iter_mut() {
+            if walker.age >= settings.lifetime {
+                walker.pos = random_floor(&nm, rng);
+                walker.age = 0;
+            }
+
+            walker.pos = step(walker.pos, width, height, rng);
+            walker.age += 1;
+
+            if nm.get(&walker.pos) == 1 {
+                nm.set(&walker.pos, 0);
+                open += 1;
+            }
+        }
+    }
+    nm
+}
+
+pub fn generate_drunkard<R: Rng>(
+    width: usize,
+    height: usize,
+    settings: &DrunkardGenerator,
+    rng: &mut R,
+) -> MapInfo {
+    let nm = dig(width, height, settings, rng);
+    finish_map(nm, &[], vec![], &Tunnels::default(), &TerrainRules::default(), rng)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mapgen::seeded_rng;
+
+    #[test]
+    fn test_dig_reaches_target() {
+        let settings = DrunkardGenerator::new(3, 35, 100);
+        let nm = dig(60, 40, &settings, &mut seeded_rng(3));
+
+        let open = nm.count_where(|&c| c == 0);
+        for ((x, y), &c) in nm.enumerate() {
+            if x == 0 || y == 0 || x == 59 || y == 39 {
+                assert_eq!(c, 1);
+            }
+        }
+        assert!(open >= 60 * 40 * 35 / 100);
+    }
+
+    #[test]
+    fn test_drunkard_map() {
+        let map = generate_drunkard(80, 50, &DrunkardGenerator::default(), &mut seeded_rng(8));
+        map.walls.print();
+
+        assert!(map.walls.count_where(|&c| c != '#') >= 80 * 50 * 40 / 100);
+        assert!(map.walls.get(&map.start) != '#');
+        assert!(map.walls.get(&map.end) != '#');
+        assert!(map.start != map.end);
+    }
+
+    #[test]
+    fn test_drunkard_same_seed() {
+        let settings = DrunkardGenerator::default();
+        let a = generate_drunkard(40, 30, &settings, &mut seeded_rng(21));
+        let b = generate_drunkard(40, 30, &settings, &mut seeded_rng(21));
+
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::Path;
+
+use grid::BitGrid;
+use mapgen::prefab::parse_with_markers;
+use mapgen::terrain::terrain_colors;
+use mapgen::{fill_map, region_colors, MapInfo};
+
+/// Reads a hand-made level. The format is the one of the vault files, with
+/// `@` marking where the player starts and `>` the exit, both exactly once.
+/// Spaces are rock, so the art does not need to spell out every wall, but
+/// floor, like any other terrain except rock, must not touch the map edge.
+///
+/// Unlike generated maps the level is used as drawn: regions that are not
+/// connected stay that way.
+pub fn parse_level(name: &str, text: &str) -> Result<MapInfo, String> {
+    let (prefab, markers) = parse_with_markers(name, text, &['@', '>'])?;
+
+    let find = |glyph: char| {
+        let found: Vec<(usize, usize)> = markers
+            .iter()
+            .filter(|&&(c, _)| c == glyph)
+            .map(|&(_, pos)| pos)
+            .collect();
+        match found.len() {
+            1 => Ok(found[0]),
+            n => Err(format!("{}: expected one `{}`, found {}", name, glyph, n)),
+        }
+    };
+    let start = find('@')?;
+    let end = find('>')?;
+
+    let (width, height) = (prefab.tiles.width, prefab.tiles.height);
+    let walls = prefab.tiles.map(|&c| if c == ' ' { '#' } else { c });
+    for ((x, y), &tile) in walls.enumerate() {
+        let edge = x == 0 || y == 0 || x + 1 == width || y + 1 == height;
+        if edge && tile != '#' {
+            return Err(format!("{}: floor on the map edge at {:?}", name, (x, y)));
+        }
+    }
+    let mut nm = walls.map(|&c| if c == '#' { 1 } else { 0 });
+    let regions = fill_map(&mut nm);
+    let mut colors = region_colors(&nm);
+    terrain_colors(&walls, &mut colors);
+
+    Ok(MapInfo {
+        walls,
+        colors,
+        blocked: BitGrid::new(width, height, false),
+        visible: BitGrid::new(width, height, true),
+        start,
+        end,
+        spawns: prefab.spawns,
+        regions,
+    })
+}
+
+pub fn load_level(path: &Path) -> Result<MapInfo, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let name = path.file_stem().unwrap().to_string_lossy();
+    parse_level(&name, &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mapgen::{SpawnKind, WALL_COLOR};
+
+    const ROOM: &str = "m = monster Gorok
+---
+ #####
+ #@.m#
+ #..>#
+ #####
+";
+
+    #[test]
+    fn test_parse_level() {
+        let level = parse_level("room", ROOM).unwrap();
+        level.walls.print();
+
+        assert_eq!(level.start, (2, 1));
+        assert_eq!(level.end, (4, 2));
+        assert_eq!(level.walls.get(&(0, 0)), '#');
+        assert_eq!(level.walls.get(&(2, 1)), '.');
+        assert_eq!(level.walls.get(&(4, 2)), '.');
+        assert!(level.colors.get(&(3, 1)) != WALL_COLOR);
+        assert_eq!(level.spawns.len(), 1);
+        assert_eq!(level.spawns[0].kind, SpawnKind::Monster("Gorok".to_string()));
+        assert_eq!(level.spawns[0].pos, (4, 1));
+    }
+
+    #[test]
+    fn test_level_needs_start_and_exit() {
+        assert!(parse_level("x", "---\n#@.#\n").is_err());
+        assert!(parse_level("x", "---\n#@>@#\n").is_err());
+        assert!(parse_level("x", "@ = door\n---\n#@>#\n").is_err());
+    }
+
+    #[test]
+    fn test_floor_on_edge() {
+        // Every edge, the region fill must never step off the map.
+        for text in [
+            "---\n#.##\n#@>#\n####\n",
+            "---\n####\n.@>#\n####\n",
+            "---\n####\n#@>.\n####\n",
+            "---\n####\n#@>#\n##.#\n",
+            "---\n####\n#@>#\n#~##\n",
+        ].iter()
+        {
+            let err = parse_level("x", text).unwrap_err();
+            assert!(err.contains("map edge"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_level_with_terrain() {
+        let level = parse_level("pool", "---\n######\n#@~=>#\n######\n").unwrap();
+
+        assert_eq!(level.walls.get(&(2, 1)), '~');
+        assert_eq!(level.walls.get(&(3, 1)), '=');
+        assert_eq!(level.regions, 1);
+        assert!(level.colors.get(&(2, 1)) != level.colors.get(&(1, 1)));
+    }
+
+    #[test]
+    fn test_load_shipped_levels() {
+        let level = load_level(Path::new("levels/tutorial.txt")).unwrap();
+
+        assert!(level.walls.get(&level.start) != '#');
+        assert!(!level.spawns.is_empty());
+    }
+}
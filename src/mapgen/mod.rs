@@ -1,51 +1,140 @@
 use std::collections::{HashMap, VecDeque};
+use std::fs::File;
 use std::hash::Hash;
+use std::path::Path;
 
-use grid::NodeMap;
+use grid::{BitGrid, NodeMap, Rect};
 
-use rand;
-use rand::Rng;
+use rand::{Rng, SeedableRng, XorShiftRng};
 
 use tcod::colors;
 
-use pathfinding::bfs;
-use pathfinding::Grid;
-
+use serde_json;
+
+
+mod bsp;
+mod connect;
+mod drunkard;
+mod level;
+mod prefab;
+mod stats;
+mod terrain;
+
+pub use self::bsp::{BspGenerator, Corridor};
+pub use self::connect::{TunnelStyle, Tunnels};
+pub use self::drunkard::DrunkardGenerator;
+pub use self::level::{load_level, parse_level};
+pub use self::prefab::{load_vaults, Prefab, Spawn, SpawnKind};
+pub use self::stats::{map_stats, MapStats, Thresholds};
+pub use self::terrain::{Terrain, TerrainRules, TERRAINS};
+
+use self::connect::{connect_rooms, tunnel_doors};
+use self::terrain::add_terrain;
+
+/// Directory the vault prefabs are read from.
+pub const VAULT_DIR: &str = "vaults";
+/// JSON file with the `CaveRules` used by the "cave" generator.
+pub const CAVE_RULES_FILE: &str = "cave_rules.json";
+
+/// Palette the generators pick region colors from.
+pub const ROOM_COLORS: [colors::Color; 8] = [
+    colors::LIGHT_BLUE,
+    colors::RED,
+    colors::GREEN,
+    colors::CYAN,
+    colors::FUCHSIA,
+    colors::AMBER,
+    colors::HAN,
+    colors::PURPLE,
+];
+
+pub const WALL_COLOR: colors::Color = colors::WHITE;
+/// Floor that does not belong to any region, such as connecting tunnels.
+pub const CORRIDOR_COLOR: colors::Color = colors::LIGHT_GREY;
+
+/// Binary map value of rock that tunnels never dig through, like the walls
+/// of vaults. Plain rock is 1.
+const SOLID: i32 = -1;
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct MapInfo {
     pub walls: NodeMap<char>,
     pub colors: NodeMap<colors::Color>,
-    pub blocked: NodeMap<bool>,
-    pub visible: NodeMap<bool>,
+    pub blocked: BitGrid,
+    pub visible: BitGrid,
     pub start: (usize, usize),
     pub end: (usize, usize),
+    pub spawns: Vec<Spawn>,
+    /// Separate floor regions before the generator connected them.
+    pub regions: usize,
 }
 
-fn flood_fill(start: (i32, i32), m2: &mut NodeMap<i32>, color: i32) {
-    let (x, y) = start;
+/// A level layout algorithm. Every generator returns the same `MapInfo`,
+/// so the game does not care which one built the level.
+pub trait MapGenerator {
+    fn name(&self) -> &str;
 
-    assert_eq!(m2.get(&(x as usize, y as usize)), 0);
+    fn generate(&self, width: usize, height: usize, rng: &mut XorShiftRng) -> MapInfo;
+}
+
+/// Names accepted by `generator_by_name`.
+pub const GENERATOR_NAMES: &[&str] = &["cave", "bsp", "bsp-astar", "drunkard"];
+
+/// Returns the generator registered under `name` with its default settings.
+/// Fails for unknown names and for vaults in `VAULT_DIR` that do not load.
+/// Without a `CAVE_RULES_FILE` the cave uses the default rules, one that
+/// is there but broken is an error too.
+pub fn generator_by_name(name: &str) -> Result<Box<dyn MapGenerator>, String> {
+    match name {
+        "cave" => {
+            let vaults = load_vaults(Path::new(VAULT_DIR))?;
+            let rules_file = Path::new(CAVE_RULES_FILE);
+            let rules = if rules_file.exists() {
+                load_rules(rules_file)?
+            } else {
+                CaveRules::default()
+            };
+            Ok(Box::new(CaveGenerator {
+                rules,
+                vaults,
+                vault_count: 2,
+                thresholds: Some(Thresholds {
+                    min_open_ratio: 0.35,
+                    min_path_length: 30,
+                    ..Thresholds::default()
+                }),
+                ..CaveGenerator::default()
+            }))
+        }
+        "bsp" => Ok(Box::new(BspGenerator::default())),
+        "bsp-astar" => Ok(Box::new(BspGenerator {
+            corridor: Corridor::AStar,
+            ..BspGenerator::default()
+        })),
+        "drunkard" => Ok(Box::new(DrunkardGenerator::default())),
+        _ => Err(format!(
+            "unknown generator '{}', expected one of {:?}",
+            name, GENERATOR_NAMES
+        )),
+    }
+}
+
+fn flood_fill(start: (usize, usize), m2: &mut NodeMap<i32>, color: i32) {
+    assert_eq!(m2.get(&start), 0);
 
     let mut queue = VecDeque::new();
-    queue.push_back((x, y));
+    queue.push_back(start);
 
-    while !queue.is_empty() {
-        let (x1, y1) = queue.pop_front().unwrap();
-        if m2.get(&(x1 as usize, y1 as usize)) != 0 {
+    while let Some(p) = queue.pop_front() {
+        if m2.get(&p) != 0 {
             continue;
         }
-        m2.set(&(x1 as usize, y1 as usize), color);
+        m2.set(&p, color);
 
-        if m2.get(&(x1 as usize, (y1 - 1) as usize)) == 0 {
-            queue.push_back((x1, y1 - 1))
-        }
-        if m2.get(&(x1 as usize, (y1 + 1) as usize)) == 0 {
-            queue.push_back((x1, y1 + 1))
-        }
-        if m2.get(&((x1 - 1) as usize, y1 as usize)) == 0 {
-            queue.push_back((x1 - 1, y1))
-        }
-        if m2.get(&((x1 + 1) as usize, y1 as usize)) == 0 {
-            queue.push_back((x1 + 1, y1))
+        for n in m2.neighbours4(&p) {
+            if m2.get(&n) == 0 {
+                queue.push_back(n)
+            }
         }
     }
 }
@@ -54,13 +143,12 @@ fn fill_map(mut nm: &mut NodeMap<i32>) -> usize {
     let height = nm.height;
     let width = nm.width;
 
-    let mut colors = 2..999;
     let mut count = 0;
 
     for x in 0..width {
         for y in 0..height {
             if nm.get(&(x, y)) == 0 {
-                flood_fill((x as i32, y as i32), &mut nm, colors.next().unwrap());
+                flood_fill((x, y), &mut nm, count as i32 + 2);
                 count += 1;
             }
         }
@@ -68,31 +156,26 @@ fn fill_map(mut nm: &mut NodeMap<i32>) -> usize {
     count
 }
 
+/// Cells per value, smallest first. Equal sizes are ordered by value so
+/// the result does not depend on hashing.
 fn room_sizes<T: Ord + Hash + Eq + Clone>(m: &NodeMap<T>, exclude: &[T]) -> Vec<(T, usize)> {
     let mut size = HashMap::new();
 
-    let height = m.height;
-    let width = m.width;
-
-    for x in 0..width {
-        for y in 0..height {
-            let tile = m.get(&(x, y)).clone();
-            if !exclude.contains(&tile) && !size.contains_key(&tile) {
-                size.insert(tile, 1);
-            } else if !exclude.contains(&tile) {
-                let c = &size[&tile] + 1;
-                size.insert(tile, c);
-            }
-        }
+    for tile in m.iter().filter(|t| !exclude.contains(t)) {
+        *size.entry(tile.clone()).or_insert(0) += 1;
     }
     let mut ret = size.into_iter().collect::<Vec<(T, usize)>>();
-    ret.sort_by(|a, b| a.1.cmp(&b.1));
+    ret.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
     ret
 }
 
-fn new_binary_nodemap(width: usize, height: usize, probability: usize) -> NodeMap<i32> {
+fn new_binary_nodemap<R: Rng>(
+    width: usize,
+    height: usize,
+    probability: usize,
+    rng: &mut R,
+) -> NodeMap<i32> {
     let mut v: Vec<i32> = Vec::new();
-    let mut rng = rand::thread_rng();
 
     for _ in 0..(width * height) {
         let roll = rng.gen_range(0, 100);
@@ -107,226 +190,343 @@ fn new_binary_nodemap(width: usize, height: usize, probability: usize) -> NodeMa
 }
 
 fn fill_edges_with<C: Clone>(nm: &mut NodeMap<C>, c: C) {
-    for y in 0..nm.height {
-        for x in 0..nm.width {
-            if x == 0 || x == nm.width - 1 || y == 0 || y == nm.height - 1 {
-                nm.set(&(x, y), c.clone());
-            }
+    let bounds = nm.bounds();
+    nm.fill_border(bounds, 1, c);
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Neighbourhood {
+    /// The full square around a cell.
+    Moore,
+    /// Only cells within `radius` steps along the axes.
+    VonNeumann,
+}
+
+/// Birth/survival rules of the cave automaton and how its regions are
+/// connected. A cell counts its rock neighbours, cells outside the map
+/// count as rock.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct CaveRules {
+    /// Rock neighbour counts that turn floor into rock.
+    pub birth: Vec<usize>,
+    /// Rock neighbour counts that keep rock as rock.
+    pub survival: Vec<usize>,
+    pub radius: usize,
+    pub neighbourhood: Neighbourhood,
+    /// How the regions left by the automaton are connected.
+    #[serde(default)]
+    pub tunnels: Tunnels,
+    /// Water, lava, chasms and rubble added to the connected cave.
+    #[serde(default)]
+    pub terrain: TerrainRules,
+}
+
+impl Default for CaveRules {
+    fn default() -> CaveRules {
+        CaveRules {
+            birth: vec![5, 6, 7, 8],
+            survival: vec![3, 4, 5, 6, 7, 8],
+            radius: 1,
+            neighbourhood: Neighbourhood::Moore,
+            tunnels: Tunnels::default(),
+            terrain: TerrainRules::default(),
         }
     }
 }
 
-fn automaton(nm: &mut NodeMap<i32>) {
-    let death_limit = 3;
-    let birth_limit = 4;
+/// Reads rules written as JSON, e.g. by a designer tuning caves.
+pub fn load_rules(path: &Path) -> Result<CaveRules, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e))
+}
 
-    for y in 0..nm.height {
-        for x in 0..nm.width {
-            let alive = count_alive_neighbours(nm, &(x as i32, y as i32));
+/// One synchronous generation: every cell is decided from the previous
+/// generation only.
+fn automaton(nm: &NodeMap<i32>, rules: &CaveRules) -> NodeMap<i32> {
+    let next = nm
+        .enumerate()
+        .map(|((x, y), &cell)| {
+            let alive = count_alive_neighbours(nm, &(x as i32, y as i32), rules);
 
-            if nm.get(&(x, y)) == 1 {
-                if alive < death_limit {
-                    nm.set(&(x, y), 0);
-                } else {
-                    nm.set(&(x, y), 1);
-                }
-            } else if alive > birth_limit {
-                nm.set(&(x, y), 1);
+            let rock = if cell == 1 {
+                rules.survival.contains(&alive)
             } else {
-                nm.set(&(x, y), 0);
-            }
-        }
-    }
+                rules.birth.contains(&alive)
+            };
+            rock as i32
+        })
+        .collect();
+    NodeMap::from_vec(nm.width, nm.height, next)
 }
 
-fn count_alive_neighbours(nm: &NodeMap<i32>, p: &(i32, i32)) -> i32 {
-    let height = nm.height as i32;
-    let width = nm.width as i32;
+fn count_alive_neighbours(nm: &NodeMap<i32>, p: &(i32, i32), rules: &CaveRules) -> usize {
+    let r = rules.radius as i32;
 
     let mut alive = 0;
 
-    for i in (p.0 - 1)..(p.0 + 2) {
-        for j in (p.1 - 1)..(p.1 + 2) {
-            if i < 0 || j < 0 || i >= width || j >= height {
-                alive += 1
-            } else if i == p.0 && j == p.1 {
+    for j in -r..r + 1 {
+        for i in -r..r + 1 {
+            if i == 0 && j == 0 {
                 continue;
-            } else {
-                alive += nm.get(&(i as usize, j as usize))
             }
+            if rules.neighbourhood == Neighbourhood::VonNeumann && i.abs() + j.abs() > r {
+                continue;
+            }
+
+            alive += nm.try_get_signed(&(p.0 + i, p.1 + j)).unwrap_or(1) as usize;
         }
     }
     alive
 }
 
-fn search_closest_value<C: Eq + Hash + Clone>(
-    haystack: &NodeMap<C>,
-    start: &(usize, usize),
-    needles: &[C],
-) -> Option<Vec<(usize, usize)>> {
-    let mut gridmap = Grid::new(haystack.width, haystack.height);
-    gridmap.fill();
-
-    bfs(
-        start,
-        |x| gridmap.neighbours(x),
-        |x| needles.contains(&haystack.get(x)),
-    )
+/// Creates the random number generator used for a given map seed.
+pub fn seeded_rng(seed: u64) -> XorShiftRng {
+    let low = seed as u32;
+    let high = (seed >> 32) as u32;
+    // XorShiftRng refuses an all-zero seed, the constants keep it non-zero.
+    XorShiftRng::from_seed([low, high, low ^ 0x9E37_79B9, high ^ 0x7F4A_7C15])
 }
 
-fn connect_rooms(nm: &NodeMap<i32>, number_rooms: usize) -> NodeMap<i32> {
-    let mut rooms: Vec<i32> = (2..).take(number_rooms).collect();
-
-    let mut nm_connected = nm.clone();
-
-    'outer: while rooms.len() > 1 {
-        let points = randomize_points_usize(nm.width, nm.height);
-        for point in points {
-            let room = nm_connected.get(&point);
-            if room != 1 && rooms.contains(&room) {
-                let goal = rooms
-                    .clone()
-                    .into_iter()
-                    .filter(|x| *x != room)
-                    .collect::<Vec<i32>>();
-
-                let mut closest = search_closest_value(&nm_connected, &point, &goal);
-
-                let target = closest.unwrap().pop().unwrap();
-                let old_room = nm_connected.get(&target);
-
-                let mut edge = search_closest_value(&nm_connected, &target, &[room]);
-
-                for p in edge.unwrap() {
-                    nm_connected.set(&p, room);
-                }
-
-                change_nodes(&mut nm_connected, old_room, room);
-
-                let index = rooms.iter().position(|&r| r == old_room).unwrap();
-
-                rooms.remove(index);
-                continue 'outer;
-            }
-        }
-    }
-    nm_connected
+/// Generates a cave that is identical for identical arguments and seed.
+pub fn generate_cave(
+    width: usize,
+    height: usize,
+    generations: usize,
+    fill_percentage: usize,
+    rules: &CaveRules,
+    seed: u64,
+) -> MapInfo {
+    generate_cave_with_rng(
+        width,
+        height,
+        generations,
+        fill_percentage,
+        rules,
+        &mut seeded_rng(seed),
+    )
 }
 
-fn change_nodes(nm: &mut NodeMap<i32>, from: i32, to: i32) {
-    for x in 0..nm.width {
-        for y in 0..nm.height {
-            if nm.get(&(x, y)) == from {
-                nm.set(&(x, y), to);
-            }
-        }
-    }
+pub fn generate_cave_with_rng<R: Rng>(
+    width: usize,
+    height: usize,
+    generations: usize,
+    fill_percentage: usize,
+    rules: &CaveRules,
+    rng: &mut R,
+) -> MapInfo {
+    let nm = cave_nodemap(width, height, generations, fill_percentage, rules, rng);
+    finish_map(nm, &[], vec![], &rules.tunnels, &rules.terrain, rng)
 }
 
-pub fn generate_cave(
+fn cave_nodemap<R: Rng>(
     width: usize,
     height: usize,
     generations: usize,
     fill_percentage: usize,
-) -> MapInfo {
-    let mut nm = new_binary_nodemap(width, height, fill_percentage);
+    rules: &CaveRules,
+    rng: &mut R,
+) -> NodeMap<i32> {
+    let mut nm = new_binary_nodemap(width, height, fill_percentage, rng);
 
     fill_edges_with(&mut nm, 1);
 
     for _ in 0..generations {
-        automaton(&mut nm)
+        nm = automaton(&nm, rules);
+        // Loose rules could open the border, keep the map closed.
+        fill_edges_with(&mut nm, 1);
     }
+    nm
+}
+
+/// Shared tail of the generators that work on a binary map (1 = rock):
+/// picks start and exit, colors the regions, connects them, adds the
+/// terrain and builds the `MapInfo`. Start and exit are never inside the
+/// `vaults` areas, the `spawns` already placed on the map, like those of
+/// vaults, are kept.
+fn finish_map<R: Rng>(
+    mut nm: NodeMap<i32>,
+    vaults: &[Rect],
+    spawns: Vec<Spawn>,
+    tunnels: &Tunnels,
+    terrain: &TerrainRules,
+    rng: &mut R,
+) -> MapInfo {
+    let width = nm.width;
+    let height = nm.height;
 
-    let (start, end) = find_start_and_exit(&nm);
+    let (start, end) = find_start_and_exit(&nm, vaults, rng);
 
     let _rooms = fill_map(&mut nm);
 
-    let mut mat: NodeMap<char> = NodeMap::new(width, height, '.');
-    let mut colormat: NodeMap<colors::Color> = NodeMap::new(width, height, colors::WHITE);
-
-    let colvec = vec![
-        colors::LIGHT_BLUE,
-        colors::RED,
-        colors::GREEN,
-        colors::CYAN,
-        colors::FUCHSIA,
-        colors::AMBER,
-        colors::HAN,
-        colors::PURPLE,
-    ];
-
-    for y in 0..height {
-        for x in 0..width {
-            let color = match nm.get(&(x, y)) {
-                1 => colors::WHITE,
-                z => colvec[((z + 5) % 8) as usize],
-            };
-            colormat.set(&(x, y), color)
-        }
-    }
+    let colormat = region_colors(&nm);
 
-    nm = connect_rooms(&nm, _rooms);
+    let filled = nm;
+    let (connected, mouths) = connect_rooms(&filled, _rooms, tunnels, rng);
+    nm = connected;
+    let spawns = tunnel_doors(&nm, &mouths, tunnels, rng)
+        .into_iter()
+        .map(|pos| Spawn {
+            kind: SpawnKind::Door,
+            glyph: '+',
+            pos,
+        })
+        .chain(spawns)
+        .filter(|s| s.pos != start && s.pos != end)
+        .collect();
 
-    for y in 0..height {
-        for x in 0..width {
-            let c = match nm.get(&(x, y)) {
-                1 => '#',
-                _ => '.',
-            };
-            mat.set(&(x, y), c);
+    let mat = nm.map(|&cell| if cell == 1 || cell == SOLID { '#' } else { '.' });
+    // Tunnels were rock when the regions were colored.
+    let colormat = colormat.zip_with(&mat, |&color, &c| {
+        if c == '.' && color == WALL_COLOR {
+            CORRIDOR_COLOR
+        } else {
+            color
         }
-    }
+    });
 
-    let mp: MapInfo = MapInfo {
+    let mut mp: MapInfo = MapInfo {
         walls: mat,
         colors: colormat,
-        blocked: NodeMap::new(width, height, false),
-        visible: NodeMap::new(width, height, true),
+        blocked: BitGrid::new(width, height, false),
+        visible: BitGrid::new(width, height, true),
         start,
         end,
+        spawns,
+        regions: _rooms,
     };
+    add_terrain(&mut mp, &filled, terrain, rng);
     mp
 }
 
-fn randomize_points_usize(x: usize, y: usize) -> Vec<(usize, usize)> {
-    let mut points: Vec<(usize, usize)> = vec![];
+/// Colors every region of a map numbered by `fill_map`, rock gets
+/// `WALL_COLOR`.
+fn region_colors(filled: &NodeMap<i32>) -> NodeMap<colors::Color> {
+    filled.map(|&z| match z {
+        1 | SOLID => WALL_COLOR,
+        z => ROOM_COLORS[((z + 5) % 8) as usize],
+    })
+}
 
-    for _x in 0..x {
-        for _y in 0..y {
-            points.push((_x, _y));
+/// Cellular automaton caves, see `generate_cave`.
+#[derive(Clone, Debug, new)]
+pub struct CaveGenerator {
+    pub generations: usize,
+    pub fill_percentage: usize,
+    #[new(default)]
+    pub rules: CaveRules,
+    /// Prefabs stamped into the cave before its regions are connected.
+    #[new(default)]
+    pub vaults: Vec<Prefab>,
+    #[new(default)]
+    pub vault_count: usize,
+    /// Caves failing these are generated again, see `CAVE_ATTEMPTS`.
+    #[new(default)]
+    pub thresholds: Option<Thresholds>,
+}
+
+/// How many caves `CaveGenerator::generate` tries to meet its thresholds.
+/// The last attempt is used even if it misses them too.
+pub const CAVE_ATTEMPTS: usize = 20;
+
+impl Default for CaveGenerator {
+    fn default() -> CaveGenerator {
+        CaveGenerator::new(3, 40)
+    }
+}
+
+impl MapGenerator for CaveGenerator {
+    fn name(&self) -> &str {
+        "cave"
+    }
+
+    fn generate(&self, width: usize, height: usize, rng: &mut XorShiftRng) -> MapInfo {
+        match self.thresholds {
+            Some(ref thresholds) => self.attempt(width, height, CAVE_ATTEMPTS, thresholds, rng).0,
+            None => self.generate_once(width, height, rng),
         }
     }
+}
 
-    let mut rng = rand::thread_rng();
-    let slice = points.as_mut_slice();
-    rng.shuffle(slice);
+impl CaveGenerator {
+    /// Throws caves away until one passes the thresholds, the default ones
+    /// if there are none. Gives up after `attempts` caves with the failures
+    /// of the last one.
+    pub fn generate_until(
+        &self,
+        width: usize,
+        height: usize,
+        attempts: usize,
+        rng: &mut XorShiftRng,
+    ) -> Result<MapInfo, String> {
+        let thresholds = self.thresholds.clone().unwrap_or_default();
+        let (cave, failures) = self.attempt(width, height, attempts, &thresholds, rng);
+        if failures.is_empty() {
+            Ok(cave)
+        } else {
+            Err(format!("no cave in {} attempts, last one: {}", attempts, failures.join(", ")))
+        }
+    }
 
-    slice.to_vec()
+    /// Generates caves until one passes `thresholds`, at most `attempts` of
+    /// them. Returns the last cave with the thresholds it failed.
+    fn attempt(
+        &self,
+        width: usize,
+        height: usize,
+        attempts: usize,
+        thresholds: &Thresholds,
+        rng: &mut XorShiftRng,
+    ) -> (MapInfo, Vec<String>) {
+        let mut cave = self.generate_once(width, height, rng);
+        let mut failures = thresholds.failures(&map_stats(&cave));
+        for _ in 1..attempts {
+            if failures.is_empty() {
+                break;
+            }
+            cave = self.generate_once(width, height, rng);
+            failures = thresholds.failures(&map_stats(&cave));
+        }
+        (cave, failures)
+    }
+
+    fn generate_once(&self, width: usize, height: usize, rng: &mut XorShiftRng) -> MapInfo {
+        let mut nm = cave_nodemap(
+            width,
+            height,
+            self.generations,
+            self.fill_percentage,
+            &self.rules,
+            rng,
+        );
+        let (spawns, areas) = prefab::stamp_vaults(&mut nm, &self.vaults, self.vault_count, rng);
+
+        finish_map(nm, &areas, spawns, &self.rules.tunnels, &self.rules.terrain, rng)
+    }
 }
 
-fn randomize_points_i32(x: usize, y: usize) -> Vec<(i32, i32)> {
-    let mut points: Vec<(i32, i32)> = vec![];
+fn randomize_points_usize<R: Rng>(x: usize, y: usize, rng: &mut R) -> Vec<(usize, usize)> {
+    let mut points: Vec<(usize, usize)> = vec![];
 
     for _x in 0..x {
         for _y in 0..y {
-            points.push((_x as i32, _y as i32));
+            points.push((_x, _y));
         }
     }
 
-    let mut rng = rand::thread_rng();
     let slice = points.as_mut_slice();
     rng.shuffle(slice);
 
     slice.to_vec()
 }
 
-fn find_start_and_exit(nm: &NodeMap<i32>) -> ((usize, usize), (usize, usize)) {
-    let mut start_points = randomize_points_usize(nm.width, nm.height);
+fn find_start_and_exit<R: Rng>(
+    nm: &NodeMap<i32>,
+    avoid: &[Rect],
+    rng: &mut R,
+) -> ((usize, usize), (usize, usize)) {
+    let mut start_points = randomize_points_usize(nm.width, nm.height, rng);
 
-    start_points = start_points
-        .into_iter()
-        .filter(|z| nm.get(z) != 1)
-        .collect();
+    start_points.retain(|z| nm.get(z) == 0 && !avoid.iter().any(|area| area.contains(z)));
 
     let start = start_points.pop().unwrap();
     let end = start_points.pop().unwrap();
@@ -345,18 +545,19 @@ mod tests {
     fn test_count_alive() {
         let m = NodeMap::from_vec(3, 3, vec![1, 0, 1, 1, 0, 1, 1, 0, 1]);
 
-        assert_eq!(count_alive_neighbours(&m, &(1, 1)), 6);
+        assert_eq!(count_alive_neighbours(&m, &(1, 1), &CaveRules::default()), 6);
     }
 
     #[test]
     fn test_fill_dungeon() {
-        let mut m2 = new_binary_nodemap(60, 35, 40);
+        let mut m2 = new_binary_nodemap(60, 35, 40, &mut seeded_rng(1));
         fill_edges_with(&mut m2, 1);
 
         m2.print();
-        automaton(&mut m2);
-        automaton(&mut m2);
-        automaton(&mut m2);
+        let rules = CaveRules::default();
+        m2 = automaton(&m2, &rules);
+        m2 = automaton(&m2, &rules);
+        m2 = automaton(&m2, &rules);
 
         m2.print();
 
@@ -473,50 +674,251 @@ mod tests {
 
     #[test]
     fn test_binary_nodemap() {
-        let nm = new_binary_nodemap(20, 20, 40);
+        let nm = new_binary_nodemap(20, 20, 40, &mut seeded_rng(2));
         nm.print();
     }
 
     #[test]
     fn test_fill_edges_with() {
-        let mut nm = new_binary_nodemap(5, 7, 0);
+        let mut nm = new_binary_nodemap(5, 7, 0, &mut seeded_rng(3));
         fill_edges_with(&mut nm, 1);
         nm.print();
     }
 
     #[test]
-    fn test_search_closest() {
-        let mut nm = NodeMap::new(10, 10, '0');
-        fill_edges_with(&mut nm, '1');
+    fn test_count_alive_von_neumann() {
+        let m = NodeMap::from_vec(3, 3, vec![1, 0, 1, 1, 0, 1, 1, 0, 1]);
+        let rules = CaveRules {
+            neighbourhood: Neighbourhood::VonNeumann,
+            ..CaveRules::default()
+        };
+
+        assert_eq!(count_alive_neighbours(&m, &(1, 1), &rules), 2);
+        // Two of the four neighbours of a corner are outside the map.
+        assert_eq!(count_alive_neighbours(&m, &(0, 0), &rules), 3);
+    }
 
-        nm.set(&(1, 1), '2');
-        nm.set(&(5, 9), '3');
+    #[test]
+    fn test_count_alive_radius() {
+        let m = NodeMap::new(5, 5, 1);
+        let rules = CaveRules {
+            radius: 2,
+            ..CaveRules::default()
+        };
+
+        assert_eq!(count_alive_neighbours(&m, &(2, 2), &rules), 24);
+    }
 
-        let res = search_closest_value(&nm, &(1, 1), &['3']).unwrap();
-        println!("{:?}", res);
+    #[test]
+    fn test_automaton_is_synchronous() {
+        // A vertical blinker under Conway's rules flips to horizontal in
+        // one step, which only happens if all cells read the old state.
+        let rules = CaveRules {
+            birth: vec![3],
+            survival: vec![2, 3],
+            ..CaveRules::default()
+        };
+        let mut m = NodeMap::new(7, 7, 0);
+        for y in 2..5 {
+            m.set(&(3, y), 1);
+        }
+        let mut expected = NodeMap::new(7, 7, 0);
+        for x in 2..5 {
+            expected.set(&(x, 3), 1);
+        }
+
+        // Outside cells count as rock, so compare the interior only.
+        let next = automaton(&m, &rules);
+        for y in 1..6 {
+            for x in 1..6 {
+                assert_eq!(next.get(&(x, y)), expected.get(&(x, y)));
+            }
+        }
     }
 
     #[test]
-    fn test_connect_rooms() {
-        let mut nm = NodeMap::new(10, 10, 1);
-        let room1 = &[(1, 1), (1, 2), (2, 2), (2, 1)];
-        let room2 = &[(8, 8), (9, 9), (8, 9), (9, 8)];
-        let room3 = &[(3, 5), (3, 6), (4, 5), (4, 6)];
-
-        for point in room1 {
-            nm.set(&point, 2);
+    fn test_rules_from_json() {
+        let rules: CaveRules = serde_json::from_str(
+            r#"{"birth": [6, 7, 8], "survival": [4, 5, 6, 7, 8], "radius": 1, "neighbourhood": "VonNeumann"}"#,
+        ).unwrap();
+
+        assert_eq!(rules.birth, vec![6, 7, 8]);
+        assert_eq!(rules.neighbourhood, Neighbourhood::VonNeumann);
+        assert_eq!(rules.tunnels, Tunnels::default());
+
+        let shipped = load_rules(Path::new(CAVE_RULES_FILE)).unwrap();
+        assert_eq!(shipped.birth, CaveRules::default().birth);
+    }
+
+    #[test]
+    fn test_generate_until() {
+        let far = CaveGenerator {
+            thresholds: Some(Thresholds {
+                min_path_length: 40,
+                ..Thresholds::default()
+            }),
+            ..CaveGenerator::default()
+        };
+        let cave = far.generate_until(60, 40, 50, &mut seeded_rng(2)).unwrap();
+        let stats = map_stats(&cave);
+
+        assert!(stats.path_length.unwrap() >= 40);
+        assert_eq!(stats.regions_after, 1);
+
+        let impossible = CaveGenerator {
+            thresholds: Some(Thresholds {
+                min_open_ratio: 1.0,
+                ..Thresholds::default()
+            }),
+            ..CaveGenerator::default()
+        };
+        assert!(impossible.generate_until(60, 40, 3, &mut seeded_rng(2)).is_err());
+    }
+
+    #[test]
+    fn test_generate_cave_same_seed() {
+        let a = generate_cave(60, 35, 3, 40, &CaveRules::default(), 1234);
+        let b = generate_cave(60, 35, 3, 40, &CaveRules::default(), 1234);
+
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_generate_cave_different_seed() {
+        let a = generate_cave(60, 35, 3, 40, &CaveRules::default(), 1);
+        let b = generate_cave(60, 35, 3, 40, &CaveRules::default(), 2);
+
+        assert!(a.walls != b.walls);
+    }
+
+    #[test]
+    fn test_generator_by_name() {
+        for name in GENERATOR_NAMES {
+            let generator = generator_by_name(name).unwrap();
+            assert_eq!(generator.name(), *name);
         }
+        assert!(generator_by_name("no such layout").is_err());
+    }
 
-        for point in room2 {
-            nm.set(&point, 3);
+    #[test]
+    fn test_cave_generator_matches_generate_cave() {
+        let cave = CaveGenerator::default().generate(40, 30, &mut seeded_rng(99));
+
+        assert_eq!(cave, generate_cave(40, 30, 3, 40, &CaveRules::default(), 99));
+    }
+
+    #[test]
+    fn test_cave_colors() {
+        let cave = generate_cave(60, 40, 3, 40, &CaveRules::default(), 12);
+
+        for y in 0..40 {
+            for x in 0..60 {
+                let color = cave.colors.get(&(x, y));
+                if cave.walls.get(&(x, y)) == '#' {
+                    assert_eq!(color, WALL_COLOR);
+                } else {
+                    assert!(color == CORRIDOR_COLOR || ROOM_COLORS.contains(&color));
+                }
+            }
         }
+    }
 
-        for point in room3 {
-            nm.set(&point, 4);
+    #[test]
+    fn test_cave_with_vaults() {
+        let vaults = load_vaults(Path::new(VAULT_DIR)).unwrap();
+        let generator = CaveGenerator {
+            vaults,
+            vault_count: 3,
+            ..CaveGenerator::default()
+        };
+        let cave = generator.generate(80, 50, &mut seeded_rng(3));
+        cave.walls.print();
+
+        assert!(!cave.spawns.is_empty());
+        for spawn in cave.spawns.iter() {
+            assert_eq!(cave.walls.get(&spawn.pos), '.');
         }
+    }
 
-        let connected = connect_rooms(&nm, 3);
+    #[test]
+    fn test_tunnels_keep_out_of_vault_walls() {
+        let generator = CaveGenerator {
+            vaults: load_vaults(Path::new(VAULT_DIR)).unwrap(),
+            vault_count: 3,
+            ..CaveGenerator::default()
+        };
+        for seed in 0..30 {
+            let cave = generator.generate(80, 50, &mut seeded_rng(seed));
+
+            // The same steps as the generator, to see where it stamped.
+            let mut rng = seeded_rng(seed);
+            let mut nm = cave_nodemap(80, 50, 3, 40, &generator.rules, &mut rng);
+            let (_, areas) = prefab::stamp_vaults(&mut nm, &generator.vaults, 3, &mut rng);
+
+            assert!(!areas.is_empty());
+            for (p, &cell) in nm.enumerate() {
+                if cell == SOLID {
+                    assert_eq!(cave.walls.get(&p), '#', "seed {} at {:?}", seed, p);
+                }
+            }
+            for area in areas.iter() {
+                assert!(!area.contains(&cave.start) && !area.contains(&cave.end));
+            }
+            assert_eq!(map_stats(&cave).regions_after, 1, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_locked_door_guards_treasury() {
+        use grid::distance_field;
+
+        let generator = CaveGenerator {
+            vaults: vec![prefab::load_prefab(&Path::new(VAULT_DIR).join("treasury.txt")).unwrap()],
+            vault_count: 1,
+            ..CaveGenerator::default()
+        };
+        for seed in 0..30 {
+            let cave = generator.generate(80, 50, &mut seeded_rng(seed));
+
+            let mut blocked = cave.walls.clone();
+            for spawn in cave.spawns.iter().filter(|s| s.kind == SpawnKind::LockedDoor) {
+                blocked.set(&spawn.pos, '#');
+            }
+            let reach = distance_field(&blocked, &[cave.start], |&c| c != '#');
+
+            let mut gold = 0;
+            for spawn in cave.spawns.iter() {
+                match spawn.kind {
+                    SpawnKind::Item(ref name) if name == "Gold" => {
+                        gold += 1;
+                        assert_eq!(reach.get(&spawn.pos), None, "seed {}", seed);
+                    }
+                    SpawnKind::Key => assert!(reach.get(&spawn.pos).is_some(), "seed {}", seed),
+                    _ => {}
+                }
+            }
+            assert_eq!(gold, 4);
+        }
+    }
+
+    const LAYOUT_SEED_42: &str = concat!(
+        "########################\n",
+        "####...#########..######\n",
+        "###.....#######.....####\n",
+        "##.......######......###\n",
+        "#...................####\n",
+        "#........#####.....#####\n",
+        "##..#########....#######\n",
+        "##############..########\n",
+        "########################\n",
+        "########################\n",
+    );
+
+    #[test]
+    fn test_generate_cave_exact_layout() {
+        let cave = generate_cave(24, 10, 3, 40, &CaveRules::default(), 42);
+        println!("{}", cave.walls);
 
-        connected.print();
+        assert_eq!(cave.walls, LAYOUT_SEED_42.parse().unwrap());
     }
 }
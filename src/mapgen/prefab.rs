@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use grid::{NodeMap, Rect};
+use mapgen::{Terrain, SOLID};
+
+use rand::Rng;
+
+/// Something the game should create on a map cell after generation.
+#[derive(Clone, PartialEq, Debug)]
+pub enum SpawnKind {
+    Monster(String),
+    Item(String),
+    Door,
+    /// A door that only opens for someone carrying a key.
+    LockedDoor,
+    Key,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Spawn {
+    pub kind: SpawnKind,
+    pub glyph: char,
+    pub pos: (usize, usize),
+}
+
+/// A hand-authored map piece read from a text file.
+///
+/// The file starts with legend lines such as `m = monster Gorok`,
+/// `! = item Potion`, `+ = door`, `L = locked door` or `k = key`, followed
+/// by a `---` line and the ASCII art. In the art `#` is rock, `.` is floor
+/// and a space leaves the underlying map untouched. The other `Terrain`
+/// glyphs, `~` deep water, `=` lava, `:` chasm and `,` rubble, may be used
+/// too. Lines starting with `;` are comments.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Prefab {
+    pub name: String,
+    /// A `Terrain` glyph or `' '` for every cell, spawn cells are floor.
+    pub tiles: NodeMap<char>,
+    /// Spawn positions are relative to the top left corner of the prefab.
+    pub spawns: Vec<Spawn>,
+}
+
+fn parse_legend(line: &str, number: usize) -> Result<(char, SpawnKind), String> {
+    let mut parts = line.splitn(2, '=');
+    let key = parts.next().unwrap().trim();
+    let value = parts
+        .next()
+        .ok_or_else(|| format!("line {}: expected `<glyph> = <kind>`", number))?
+        .trim();
+
+    let mut chars = key.chars();
+    let glyph = match (chars.next(), chars.next()) {
+        (Some(c), None) => c,
+        _ => return Err(format!("line {}: legend key must be a single glyph", number)),
+    };
+    if glyph == ' ' || Terrain::from_glyph(glyph).is_some() {
+        return Err(format!("line {}: `{}` is reserved", number, glyph));
+    }
+
+    let mut words = value.splitn(2, ' ');
+    let kind = match (words.next(), words.next().map(str::trim)) {
+        (Some("monster"), Some(name)) if !name.is_empty() => SpawnKind::Monster(name.to_string()),
+        (Some("item"), Some(name)) if !name.is_empty() => SpawnKind::Item(name.to_string()),
+        (Some("door"), None) => SpawnKind::Door,
+        (Some("locked"), Some("door")) => SpawnKind::LockedDoor,
+        (Some("key"), None) => SpawnKind::Key,
+        _ => return Err(format!("line {}: unknown legend entry `{}`", number, value)),
+    };
+    Ok((glyph, kind))
+}
+
+pub fn parse_prefab(name: &str, text: &str) -> Result<Prefab, String> {
+    parse_with_markers(name, text, &[]).map(|(prefab, _)| prefab)
+}
+
+/// Marker glyphs found in the art with their positions.
+pub type Markers = Vec<(char, (usize, usize))>;
+
+/// Parses like `parse_prefab`, but the `markers` glyphs may be used in the
+/// art without a legend entry. Their cells become floor and their
+/// positions are returned in the order they appear.
+pub fn parse_with_markers(
+    name: &str,
+    text: &str,
+    markers: &[char],
+) -> Result<(Prefab, Markers), String> {
+    let mut legend = HashMap::new();
+    let mut rows: Vec<&str> = vec![];
+    let mut in_map = false;
+
+    for (i, line) in text.lines().enumerate() {
+        if in_map {
+            rows.push(line);
+        } else if line.trim() == "---" {
+            in_map = true;
+        } else if line.trim().is_empty() || line.starts_with(';') {
+            continue;
+        } else {
+            let (glyph, kind) = parse_legend(line, i + 1)?;
+            if markers.contains(&glyph) {
+                return Err(format!("line {}: `{}` is reserved", i + 1, glyph));
+            }
+            legend.insert(glyph, kind);
+        }
+    }
+
+    while rows.last().map_or(false, |r| r.trim().is_empty()) {
+        rows.pop();
+    }
+    if rows.is_empty() {
+        return Err(format!("{}: no map after `---`", name));
+    }
+
+    let height = rows.len();
+    let width = rows.iter().map(|r| r.chars().count()).max().unwrap();
+    let mut tiles = NodeMap::new(width, height, ' ');
+    let mut spawns = vec![];
+    let mut found = vec![];
+
+    for (y, row) in rows.iter().enumerate() {
+        for (x, c) in row.chars().enumerate() {
+            match c {
+                ' ' => tiles.set(&(x, y), c),
+                _ if Terrain::from_glyph(c).is_some() => tiles.set(&(x, y), c),
+                _ if markers.contains(&c) => {
+                    tiles.set(&(x, y), '.');
+                    found.push((c, (x, y)));
+                }
+                _ => match legend.get(&c) {
+                    Some(kind) => {
+                        tiles.set(&(x, y), '.');
+                        spawns.push(Spawn {
+                            kind: kind.clone(),
+                            glyph: c,
+                            pos: (x, y),
+                        });
+                    }
+                    None => return Err(format!("{}: `{}` is not in the legend", name, c)),
+                },
+            }
+        }
+    }
+
+    let prefab = Prefab {
+        name: name.to_string(),
+        tiles,
+        spawns,
+    };
+    Ok((prefab, found))
+}
+
+pub fn load_prefab(path: &Path) -> Result<Prefab, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let name = path.file_stem().unwrap().to_string_lossy();
+    parse_prefab(&name, &text)
+}
+
+/// Loads every `.txt` prefab in `dir`, sorted by file name. A missing
+/// directory just means there are no vaults.
+pub fn load_vaults(dir: &Path) -> Result<Vec<Prefab>, String> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().map_or(false, |e| e == "txt") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    paths.iter().map(|p| load_prefab(p)).collect()
+}
+
+/// Stamps up to `count` randomly chosen vaults into the binary map
+/// (1 = rock) and returns their spawns in map coordinates and the areas
+/// they cover. Vault walls become `SOLID`, so tunnels go around them and
+/// only enter through the vault's own openings. Every vault keeps a ring
+/// of plain rock to the map edge and to the other vaults, for tunnels to
+/// reach those openings. The binary map has no room for terrain, so water,
+/// lava and the like are stamped as floor.
+pub fn stamp_vaults<R: Rng>(
+    nm: &mut NodeMap<i32>,
+    vaults: &[Prefab],
+    count: usize,
+    rng: &mut R,
+) -> (Vec<Spawn>, Vec<Rect>) {
+    let mut spawns = vec![];
+    let mut placed: Vec<Rect> = vec![];
+    if vaults.is_empty() {
+        return (spawns, placed);
+    }
+
+    for _ in 0..count {
+        let vault = &vaults[rng.gen_range(0, vaults.len())];
+        let (w, h) = (vault.tiles.width, vault.tiles.height);
+        if w + 4 > nm.width || h + 4 > nm.height {
+            continue;
+        }
+
+        for _attempt in 0..20 {
+            let x = rng.gen_range(2, nm.width - w - 1);
+            let y = rng.gen_range(2, nm.height - h - 1);
+            let area = Rect::new(x, y, w, h);
+            let ring = Rect::new(x - 1, y - 1, w + 2, h + 2);
+
+            if placed.iter().any(|other| other.intersects(&ring)) {
+                continue;
+            }
+
+            nm.blit_with(&vault.tiles, (x, y), |cell, &c| match c {
+                '#' => *cell = SOLID,
+                ' ' => (),
+                _ => *cell = 0,
+            });
+            for spawn in vault.spawns.iter() {
+                spawns.push(Spawn {
+                    pos: (x + spawn.pos.0, y + spawn.pos.1),
+                    ..spawn.clone()
+                });
+            }
+            placed.push(area);
+            break;
+        }
+    }
+    (spawns, placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mapgen::seeded_rng;
+
+    const SHRINE: &str = "; a test vault
+m = monster Gorok
+! = item Potion
++ = door
+---
+#####
+#m.!#
+#...+
+#####
+";
+
+    #[test]
+    fn test_parse_prefab() {
+        let prefab = parse_prefab("shrine", SHRINE).unwrap();
+        prefab.tiles.print();
+
+        assert_eq!(prefab.tiles.width, 5);
+        assert_eq!(prefab.tiles.height, 4);
+        assert_eq!(prefab.tiles.get(&(1, 1)), '.');
+        assert_eq!(prefab.tiles.get(&(0, 0)), '#');
+        assert_eq!(
+            prefab.spawns,
+            vec![
+                Spawn { kind: SpawnKind::Monster("Gorok".to_string()), glyph: 'm', pos: (1, 1) },
+                Spawn { kind: SpawnKind::Item("Potion".to_string()), glyph: '!', pos: (3, 1) },
+                Spawn { kind: SpawnKind::Door, glyph: '+', pos: (4, 2) },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_prefab_errors() {
+        assert!(parse_prefab("x", "---\n#?#\n").is_err());
+        assert!(parse_prefab("x", "m = dragon\n---\n#m#\n").is_err());
+        assert!(parse_prefab("x", "# = door\n---\n#\n").is_err());
+        assert!(parse_prefab("x", "m = monster Gorok\n").is_err());
+        assert!(parse_prefab("x", "L = locked gate\n---\n#L#\n").is_err());
+        assert!(parse_prefab("x", "~ = key\n---\n#~#\n").is_err());
+    }
+
+    #[test]
+    fn test_terrain_in_art() {
+        let prefab = parse_prefab("x", "---\n#~=:,#\n").unwrap();
+
+        assert_eq!(prefab.tiles.get(&(1, 0)), '~');
+        assert_eq!(prefab.tiles.get(&(4, 0)), ',');
+    }
+
+    #[test]
+    fn test_short_rows_are_padded() {
+        let prefab = parse_prefab("x", "---\n###\n#\n").unwrap();
+
+        assert_eq!(prefab.tiles.get(&(2, 1)), ' ');
+    }
+
+    #[test]
+    fn test_stamp_vaults() {
+        let vaults = [parse_prefab("shrine", SHRINE).unwrap()];
+        let mut nm = NodeMap::new(30, 20, 0);
+
+        let (spawns, areas) = stamp_vaults(&mut nm, &vaults, 3, &mut seeded_rng(1));
+
+        assert!(!spawns.is_empty());
+        assert_eq!(spawns.len(), areas.len() * 3);
+        // Every stamped shrine brings its monster, potion and door along,
+        // at the same place relative to the shrine's corner.
+        for (shrine, area) in spawns.chunks(3).zip(areas.iter()) {
+            let (x, y) = (area.x, area.y);
+            let found: Vec<(SpawnKind, (usize, usize))> =
+                shrine.iter().map(|s| (s.kind.clone(), s.pos)).collect();
+            assert_eq!(
+                found,
+                vec![
+                    (SpawnKind::Monster("Gorok".to_string()), (x + 1, y + 1)),
+                    (SpawnKind::Item("Potion".to_string()), (x + 3, y + 1)),
+                    (SpawnKind::Door, (x + 4, y + 2)),
+                ]
+            );
+            assert_eq!(nm.get(&(x, y)), SOLID);
+        }
+        for spawn in spawns.iter() {
+            assert_eq!(nm.get(&spawn.pos), 0);
+        }
+        for x in 0..30 {
+            assert_eq!(nm.get(&(x, 0)), 0);
+            assert_eq!(nm.get(&(x, 1)), 0);
+        }
+        for (i, a) in areas.iter().enumerate() {
+            let ring = Rect::new(a.x - 1, a.y - 1, a.w + 2, a.h + 2);
+            assert!(areas[i + 1..].iter().all(|b| !b.intersects(&ring)));
+        }
+
+        // Only one shrine fits, with a ring of rock between it and the
+        // map edge.
+        let mut tight = NodeMap::new(9, 8, 0);
+        let (spawns, areas) = stamp_vaults(&mut tight, &vaults, 3, &mut seeded_rng(1));
+        let found: Vec<(SpawnKind, (usize, usize))> =
+            spawns.iter().map(|s| (s.kind.clone(), s.pos)).collect();
+        assert_eq!(
+            found,
+            vec![
+                (SpawnKind::Monster("Gorok".to_string()), (3, 3)),
+                (SpawnKind::Item("Potion".to_string()), (5, 3)),
+                (SpawnKind::Door, (6, 4)),
+            ]
+        );
+        assert_eq!(areas, vec![Rect::new(2, 2, 5, 4)]);
+    }
+
+    #[test]
+    fn test_load_shipped_vaults() {
+        let vaults = load_vaults(Path::new("vaults")).unwrap();
+
+        assert!(!vaults.is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+use grid::NodeMap;
+use mapgen::{fill_map, MapInfo};
+
+use pathfinding::bfs;
+
+/// Numbers for telling a playable map from a degenerate one.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MapStats {
+    /// Share of all cells that are floor, between 0 and 1.
+    pub open_ratio: f32,
+    /// Separate floor regions before the generator connected them.
+    pub regions_before: usize,
+    /// Separate floor regions of the finished map.
+    pub regions_after: usize,
+    /// Steps on the shortest walk from start to exit, `None` if there is
+    /// no walk at all.
+    pub path_length: Option<usize>,
+    /// Floor cells with a single floor neighbour.
+    pub dead_ends: usize,
+}
+
+/// Limits a map has to stay within, see `Thresholds::failures`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Thresholds {
+    pub min_open_ratio: f32,
+    pub min_path_length: usize,
+    pub max_dead_ends: usize,
+    pub max_regions: usize,
+}
+
+impl Default for Thresholds {
+    /// Only asks for a connected map with a way from start to exit.
+    fn default() -> Thresholds {
+        Thresholds {
+            min_open_ratio: 0.0,
+            min_path_length: 1,
+            max_dead_ends: usize::MAX,
+            max_regions: 1,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Describes every limit `stats` breaks, empty if the map is fine.
+    pub fn failures(&self, stats: &MapStats) -> Vec<String> {
+        let mut failures = vec![];
+
+        if stats.open_ratio < self.min_open_ratio {
+            failures.push(format!(
+                "open ratio {:.2} below {:.2}",
+                stats.open_ratio, self.min_open_ratio
+            ));
+        }
+        if stats.regions_after > self.max_regions {
+            failures.push(format!(
+                "{} regions, at most {} allowed",
+                stats.regions_after, self.max_regions
+            ));
+        }
+        match stats.path_length {
+            None => failures.push("exit is unreachable".to_string()),
+            Some(length) if length < self.min_path_length => failures.push(format!(
+                "exit {} steps from start, at least {} wanted",
+                length, self.min_path_length
+            )),
+            Some(_) => (),
+        }
+        if stats.dead_ends > self.max_dead_ends {
+            failures.push(format!(
+                "{} dead ends, at most {} allowed",
+                stats.dead_ends, self.max_dead_ends
+            ));
+        }
+        failures
+    }
+}
+
+fn floor_neighbours(walls: &NodeMap<char>, p: (usize, usize)) -> Vec<(usize, usize)> {
+    walls.neighbours4(&p).filter(|n| walls.get(n) != '#').collect()
+}
+
+pub fn map_stats(info: &MapInfo) -> MapStats {
+    let walls = &info.walls;
+    let open = walls.count_where(|&c| c != '#');
+    let dead_ends = walls
+        .enumerate()
+        .filter(|&(p, &c)| c != '#' && floor_neighbours(walls, p).len() == 1)
+        .count();
+    let mut nm = walls.map(|&c| if c == '#' { 1 } else { 0 });
+
+    let path = bfs(
+        &info.start,
+        |&p| floor_neighbours(walls, p),
+        |&p| p == info.end,
+    );
+
+    MapStats {
+        open_ratio: open as f32 / (walls.width * walls.height) as f32,
+        regions_before: info.regions,
+        regions_after: fill_map(&mut nm),
+        path_length: path.map(|p| p.len() - 1),
+        dead_ends,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mapgen::parse_level;
+
+    #[test]
+    fn test_map_stats() {
+        let level = parse_level(
+            "x",
+            "---\n#######\n#@...>#\n#.#####\n#######\n###.###\n#######\n",
+        ).unwrap();
+        let stats = map_stats(&level);
+
+        assert_eq!(stats.open_ratio, 7.0 / 42.0);
+        assert_eq!(stats.regions_before, 2);
+        assert_eq!(stats.regions_after, 2);
+        assert_eq!(stats.path_length, Some(4));
+        // The exit and the bottom of the bend, the lone cell has no
+        // neighbour at all.
+        assert_eq!(stats.dead_ends, 2);
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let level = parse_level("x", "---\n#####\n#@.>#\n#####\n").unwrap();
+        let stats = map_stats(&level);
+
+        assert!(Thresholds::default().failures(&stats).is_empty());
+
+        let strict = Thresholds {
+            min_open_ratio: 0.5,
+            min_path_length: 10,
+            ..Thresholds::default()
+        };
+        assert_eq!(strict.failures(&stats).len(), 2);
+    }
+}
@@ -0,0 +1,409 @@
+use grid::{distance_field, NodeMap};
+use mapgen::connect::winding_path;
+use mapgen::{room_sizes, MapInfo, SOLID};
+
+use rand::Rng;
+
+use tcod::colors::{self, Color};
+
+/// What a map cell is made of. `MapInfo.walls` holds the glyph of each.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Terrain {
+    Floor,
+    Wall,
+    /// Slows whoever wades in.
+    DeepWater,
+    /// Burns whoever steps in.
+    Lava,
+    /// Whoever steps in falls to the next level.
+    Chasm,
+    /// Slows a little, like water.
+    Rubble,
+}
+
+/// Every terrain, for looking glyphs up.
+pub const TERRAINS: [Terrain; 6] = [
+    Terrain::Floor,
+    Terrain::Wall,
+    Terrain::DeepWater,
+    Terrain::Lava,
+    Terrain::Chasm,
+    Terrain::Rubble,
+];
+
+impl Terrain {
+    pub fn from_glyph(glyph: char) -> Option<Terrain> {
+        TERRAINS.iter().cloned().find(|t| t.glyph() == glyph)
+    }
+
+    pub fn glyph(self) -> char {
+        match self {
+            Terrain::Floor => '.',
+            Terrain::Wall => '#',
+            Terrain::DeepWater => '~',
+            Terrain::Lava => '=',
+            Terrain::Chasm => ':',
+            Terrain::Rubble => ',',
+        }
+    }
+
+    pub fn walkable(self) -> bool {
+        self != Terrain::Wall
+    }
+
+    pub fn blocks_sight(self) -> bool {
+        self == Terrain::Wall
+    }
+
+    /// Turns it takes to step onto the terrain.
+    pub fn move_cost(self) -> i32 {
+        match self {
+            Terrain::DeepWater => 3,
+            Terrain::Rubble => 2,
+            _ => 1,
+        }
+    }
+
+    /// Fixed color of the terrain, `None` for floor and rock, which keep
+    /// the color of their region.
+    pub fn color(self) -> Option<Color> {
+        match self {
+            Terrain::DeepWater => Some(colors::BLUE),
+            Terrain::Lava => Some(colors::ORANGE),
+            Terrain::Chasm => Some(colors::DARKEST_GREY),
+            Terrain::Rubble => Some(colors::SEPIA),
+            Terrain::Floor | Terrain::Wall => None,
+        }
+    }
+}
+
+/// Where the terrain pass puts water, lava, chasms and rubble. Everything
+/// defaults to zero, which leaves the map as plain rock and floor.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerrainRules {
+    /// How many of the smallest regions are flooded into pools.
+    pub pools: usize,
+    /// Regions with more cells than this are never flooded.
+    pub max_pool_size: usize,
+    /// Chance from 0 to 1 that a pool is lava instead of water.
+    pub lava_chance: f32,
+    /// Chance from 0 to 1 that a pool is a chasm instead of water.
+    pub chasm_chance: f32,
+    /// Rivers of deep water winding from one side of the map to the other.
+    pub rivers: usize,
+    /// Chance from 0 to 1 for rubble on floor along the walls.
+    pub rubble_chance: f32,
+}
+
+/// Recolors the cells whose terrain has a color of its own.
+pub fn terrain_colors(walls: &NodeMap<char>, colors: &mut NodeMap<Color>) {
+    *colors = colors.zip_with(walls, |&color, &c| {
+        Terrain::from_glyph(c)
+            .and_then(Terrain::color)
+            .unwrap_or(color)
+    });
+}
+
+/// Whether the way through the map may lead over the glyph. Lava and
+/// chasms are walkable, but nobody should have to cross them.
+fn on_the_way(c: char) -> bool {
+    match Terrain::from_glyph(c) {
+        Some(Terrain::Wall) | Some(Terrain::Lava) | Some(Terrain::Chasm) | None => false,
+        Some(_) => true,
+    }
+}
+
+/// True if every cell that could be reached from `start` in `before` can
+/// still be reached in `after`, unless it stopped being on the way itself.
+fn keeps_map_connected(
+    before: &NodeMap<char>,
+    after: &NodeMap<char>,
+    start: (usize, usize),
+) -> bool {
+    let reached = distance_field(before, &[start], |&c| on_the_way(c));
+    let still = distance_field(after, &[start], |&c| on_the_way(c));
+    after.enumerate().all(|(p, &c)| {
+        !on_the_way(c) || reached.get(&p).is_none() || still.get(&p).is_some()
+    })
+}
+
+/// Floods small regions of `filled`, the map numbered by `fill_map` before
+/// the regions were connected, and runs rivers and rubble over the floor
+/// of the finished map. Only plain floor without a spawn on it changes, so
+/// rock, vault walls, start and exit stay as they are. A lava or chasm pool
+/// that would cut part of the map off from the start is water instead.
+pub fn add_terrain<R: Rng>(
+    info: &mut MapInfo,
+    filled: &NodeMap<i32>,
+    rules: &TerrainRules,
+    rng: &mut R,
+) {
+    let (width, height) = (info.walls.width, info.walls.height);
+    let (start, end) = (info.start, info.end);
+
+    let mut fixed = vec![start, end];
+    fixed.extend(info.spawns.iter().map(|s| s.pos));
+
+    if rules.pools > 0 {
+        let mut keep: Vec<i32> = fixed.iter().map(|p| filled.get(p)).collect();
+        keep.extend(&[1, SOLID]);
+        let pools: Vec<i32> = room_sizes(filled, &keep)
+            .into_iter()
+            .filter(|&(_, size)| size <= rules.max_pool_size)
+            .take(rules.pools)
+            .map(|(region, _)| region)
+            .collect();
+
+        for region in pools {
+            let roll = rng.gen::<f32>();
+            let terrain = if roll < rules.lava_chance {
+                Terrain::Lava
+            } else if roll < rules.lava_chance + rules.chasm_chance {
+                Terrain::Chasm
+            } else {
+                Terrain::DeepWater
+            };
+            let flood = |walls: &NodeMap<char>, terrain: Terrain| {
+                walls.zip_with(filled, |&c, &r| if r == region { terrain.glyph() } else { c })
+            };
+            let mut flooded = flood(&info.walls, terrain);
+            if !keeps_map_connected(&info.walls, &flooded, start) {
+                flooded = flood(&info.walls, Terrain::DeepWater);
+            }
+            info.walls = flooded;
+        }
+    }
+
+    if width > 3 && height > 3 {
+        for _ in 0..rules.rivers {
+            let from = (1, rng.gen_range(1, height - 1));
+            let to = (width - 2, rng.gen_range(1, height - 1));
+            for p in winding_path(from, to, width, height, rng) {
+                if info.walls.get(&p) == '.' && !fixed.contains(&p) {
+                    info.walls.set(&p, Terrain::DeepWater.glyph());
+                }
+            }
+        }
+    }
+
+    if rules.rubble_chance > 0.0 {
+        for y in 1..height.saturating_sub(1) {
+            for x in 1..width.saturating_sub(1) {
+                if info.walls.get(&(x, y)) != '.' || fixed.contains(&(x, y)) {
+                    continue;
+                }
+                let rock = info
+                    .walls
+                    .neighbours8(&(x, y))
+                    .filter(|p| info.walls.get(p) == '#')
+                    .count();
+                if rock >= 3 && rng.gen::<f32>() < rules.rubble_chance {
+                    info.walls.set(&(x, y), Terrain::Rubble.glyph());
+                }
+            }
+        }
+    }
+
+    terrain_colors(&info.walls, &mut info.colors);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use grid::BitGrid;
+    use mapgen::prefab::parse_prefab;
+    use mapgen::{fill_map, generate_cave, seeded_rng, CaveRules, Spawn};
+
+    const TREASURY: &str = "$ = item Gold
+k = key
+L = locked door
++ = door
+---
+#########
+#$$#....+
+#$$L..k.#
+#########
+";
+
+    fn map_info(text: &str, start: (usize, usize), end: (usize, usize), spawns: Vec<Spawn>) -> MapInfo {
+        let walls: NodeMap<char> = text.parse().unwrap();
+        let (width, height) = (walls.width, walls.height);
+        MapInfo {
+            walls,
+            colors: NodeMap::new(width, height, colors::WHITE),
+            blocked: BitGrid::new(width, height, false),
+            visible: BitGrid::new(width, height, true),
+            start,
+            end,
+            spawns,
+            regions: 1,
+        }
+    }
+
+    #[test]
+    fn test_terrain_glyphs() {
+        for terrain in TERRAINS.iter() {
+            assert_eq!(Terrain::from_glyph(terrain.glyph()), Some(*terrain));
+        }
+        assert_eq!(Terrain::from_glyph('x'), None);
+        assert!(!Terrain::Wall.walkable());
+        assert!(Terrain::Lava.walkable());
+        assert!(!Terrain::DeepWater.blocks_sight());
+        assert!(Terrain::DeepWater.move_cost() > Terrain::Floor.move_cost());
+    }
+
+    #[test]
+    fn test_smallest_regions_become_pools() {
+        // A big cave on the left and two puddles on the right.
+        let mut walls = NodeMap::new(12, 5, '#');
+        let mut nm = NodeMap::new(12, 5, 1);
+        let floor = [(1, 1), (2, 1), (3, 1), (1, 2), (2, 2), (3, 2), (1, 3), (2, 3), (3, 3)];
+        for &p in floor.iter().chain([(7, 2), (9, 2), (10, 2)].iter()) {
+            walls.set(&p, '.');
+            nm.set(&p, 0);
+        }
+        fill_map(&mut nm);
+
+        let mut info = MapInfo {
+            walls,
+            colors: NodeMap::new(12, 5, colors::WHITE),
+            blocked: BitGrid::new(12, 5, false),
+            visible: BitGrid::new(12, 5, true),
+            start: (1, 1),
+            end: (3, 3),
+            spawns: vec![],
+            regions: 3,
+        };
+        let rules = TerrainRules {
+            pools: 1,
+            max_pool_size: 5,
+            ..TerrainRules::default()
+        };
+        add_terrain(&mut info, &nm, &rules, &mut seeded_rng(1));
+
+        assert_eq!(info.walls.get(&(7, 2)), '~');
+        assert_eq!(info.colors.get(&(7, 2)), colors::BLUE);
+        assert_eq!(info.walls.get(&(9, 2)), '.');
+        assert_eq!(info.walls.get(&(2, 2)), '.');
+    }
+
+    #[test]
+    fn test_pool_on_the_way_stays_water() {
+        // The middle region lies between start and exit, the one below it
+        // is a dead end. The rock between them is tunnel by now, so only
+        // the numbering in `filled` tells the regions apart.
+        let mut info = map_info(
+            "###########\n#.........#\n#####.#####\n#####.#####\n###########\n",
+            (1, 1),
+            (9, 1),
+            vec![],
+        );
+        let mut filled = NodeMap::new(11, 5, 1);
+        for &p in [(1, 1), (2, 1), (5, 1), (5, 3), (8, 1), (9, 1)].iter() {
+            filled.set(&p, 0);
+        }
+        fill_map(&mut filled);
+        let rules = TerrainRules {
+            pools: 2,
+            max_pool_size: 5,
+            lava_chance: 1.0,
+            ..TerrainRules::default()
+        };
+        add_terrain(&mut info, &filled, &rules, &mut seeded_rng(1));
+
+        assert_eq!(info.walls.get(&(5, 1)), '~');
+        assert_eq!(info.walls.get(&(5, 3)), '=');
+    }
+
+    #[test]
+    fn test_vault_walls_survive_terrain() {
+        let vault = parse_prefab("treasury", TREASURY).unwrap();
+        let mut text = String::new();
+        for y in 0..11 {
+            for x in 0..21 {
+                let edge = x == 0 || y == 0 || x == 20 || y == 10;
+                text.push(if edge { '#' } else { '.' });
+            }
+            text.push('\n');
+        }
+        let at = (6, 4);
+        let spawns = vault
+            .spawns
+            .iter()
+            .map(|s| Spawn {
+                pos: (at.0 + s.pos.0, at.1 + s.pos.1),
+                ..s.clone()
+            })
+            .collect();
+        let mut info = map_info(&text, (1, 1), (19, 9), spawns);
+        info.walls.blit_with(&vault.tiles, at, |cell, &c| *cell = if c == '#' { '#' } else { '.' });
+        let mut filled = info.walls.map(|&c| if c == '#' { 1 } else { 0 });
+        fill_map(&mut filled);
+        let before = info.spawns.clone();
+
+        let rules = TerrainRules {
+            pools: 3,
+            max_pool_size: 100,
+            rivers: 10,
+            rubble_chance: 0.5,
+            ..TerrainRules::default()
+        };
+        add_terrain(&mut info, &filled, &rules, &mut seeded_rng(2));
+        info.walls.print();
+
+        assert!(info.walls.count_where(|&c| c == '~') > 0);
+        for ((x, y), &c) in vault.tiles.enumerate() {
+            if c == '#' {
+                assert_eq!(info.walls.get(&(at.0 + x, at.1 + y)), '#');
+            }
+        }
+        assert_eq!(info.spawns, before);
+        for spawn in info.spawns.iter() {
+            assert_eq!(info.walls.get(&spawn.pos), '.');
+        }
+    }
+
+    #[test]
+    fn test_hazards_keep_exit_reachable() {
+        let rules = CaveRules {
+            terrain: TerrainRules {
+                pools: 8,
+                max_pool_size: 200,
+                lava_chance: 0.5,
+                chasm_chance: 0.5,
+                ..TerrainRules::default()
+            },
+            ..CaveRules::default()
+        };
+        for seed in 0..20 {
+            let cave = generate_cave(60, 40, 3, 40, &rules, seed);
+            let way = distance_field(&cave.walls, &[cave.start], |&c| on_the_way(c));
+
+            assert!(way.get(&cave.end).is_some(), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_cave_with_terrain() {
+        let rules = CaveRules {
+            terrain: TerrainRules {
+                pools: 3,
+                max_pool_size: 100,
+                lava_chance: 0.5,
+                rivers: 1,
+                rubble_chance: 0.2,
+                ..TerrainRules::default()
+            },
+            ..CaveRules::default()
+        };
+        let cave = generate_cave(60, 40, 3, 40, &rules, 8);
+        cave.walls.print();
+
+        assert!(cave.walls.count_where(|&c| c == '~') > 0);
+        assert!(cave.walls.count_where(|&c| c == ',') > 0);
+        assert_eq!(cave.walls.get(&cave.start), '.');
+        assert_eq!(cave.walls.get(&cave.end), '.');
+    }
+}
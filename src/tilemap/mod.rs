@@ -0,0 +1,151 @@
+use grid::NodeMap;
+use mapgen::{MapInfo, Terrain};
+
+use tcod::colors::{self, Color};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct Tile {
+    pub kind: Terrain,
+    pub walkable: bool,
+    pub block_sight: bool,
+}
+
+impl Tile {
+    pub fn new(kind: Terrain) -> Tile {
+        Tile {
+            kind,
+            walkable: kind.walkable(),
+            block_sight: kind.blocks_sight(),
+        }
+    }
+
+    pub fn glyph(&self) -> char {
+        self.kind.glyph()
+    }
+}
+
+/// Terrain of the current level, kept next to the `Ecs` instead of
+/// one entity per cell.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TileMap {
+    pub tiles: NodeMap<Tile>,
+    /// Base color of every cell, taken from the generator's region colors.
+    pub colors: NodeMap<Color>,
+}
+
+impl TileMap {
+    pub fn from_map_info(info: &MapInfo) -> TileMap {
+        TileMap {
+            tiles: info
+                .walls
+                .map(|&c| Tile::new(Terrain::from_glyph(c).unwrap_or(Terrain::Floor))),
+            colors: info.colors.clone(),
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.tiles.width as i32
+    }
+
+    pub fn height(&self) -> i32 {
+        self.tiles.height as i32
+    }
+
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        self.tiles.in_bounds_signed(&(x, y))
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<Tile> {
+        self.tiles.try_get_signed(&(x, y))
+    }
+
+    pub fn color(&self, x: i32, y: i32) -> Color {
+        self.colors.try_get_signed(&(x, y)).unwrap_or(colors::BLACK)
+    }
+
+    pub fn is_walkable(&self, x: i32, y: i32) -> bool {
+        self.get(x, y).map_or(false, |t| t.walkable)
+    }
+
+    pub fn blocks_sight(&self, x: i32, y: i32) -> bool {
+        self.get(x, y).map_or(true, |t| t.block_sight)
+    }
+
+    /// Terrain of a cell, outside the map is rock.
+    pub fn terrain(&self, x: i32, y: i32) -> Terrain {
+        self.get(x, y).map_or(Terrain::Wall, |t| t.kind)
+    }
+
+    /// What it takes to step onto every cell, for a `DijkstraMap`. Lava
+    /// and chasms count as blocked, nobody walks into them on purpose.
+    pub fn move_costs(&self) -> NodeMap<Option<i32>> {
+        self.tiles.map(|tile| match tile.kind {
+            Terrain::Lava | Terrain::Chasm => None,
+            kind if tile.walkable => Some(kind.move_cost()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mapgen::{generate_cave, parse_level, CaveRules};
+
+    #[test]
+    fn test_from_map_info() {
+        let cave = generate_cave(30, 20, 3, 40, &CaveRules::default(), 7);
+        let map = TileMap::from_map_info(&cave);
+
+        assert_eq!(map.width(), 30);
+        assert_eq!(map.height(), 20);
+
+        for y in 0..20 {
+            for x in 0..30 {
+                let wall = cave.walls.get(&(x, y)) == '#';
+                let tile = map.get(x as i32, y as i32).unwrap();
+                assert_eq!(tile.walkable, !wall);
+                assert_eq!(tile.block_sight, wall);
+            }
+        }
+
+        let (sx, sy) = cave.start;
+        assert!(map.is_walkable(sx as i32, sy as i32));
+        assert_eq!(map.color(sx as i32, sy as i32), cave.colors.get(&cave.start));
+    }
+
+    #[test]
+    fn test_terrain_tiles() {
+        let level = parse_level("pool", "---\n######\n#@~:>#\n######\n").unwrap();
+        let map = TileMap::from_map_info(&level);
+
+        assert_eq!(map.terrain(2, 1), Terrain::DeepWater);
+        assert_eq!(map.terrain(3, 1), Terrain::Chasm);
+        assert_eq!(map.get(2, 1).unwrap().glyph(), '~');
+        assert!(map.is_walkable(2, 1));
+        assert!(!map.blocks_sight(2, 1));
+        assert_eq!(map.terrain(-1, 1), Terrain::Wall);
+    }
+
+    #[test]
+    fn test_move_costs() {
+        let level = parse_level("pool", "---\n#######\n#@~:=>#\n#######\n").unwrap();
+        let costs = TileMap::from_map_info(&level).move_costs();
+
+        assert_eq!(costs.get(&(1, 1)), Some(1));
+        assert_eq!(costs.get(&(2, 1)), Some(3));
+        assert_eq!(costs.get(&(3, 1)), None);
+        assert_eq!(costs.get(&(4, 1)), None);
+        assert_eq!(costs.get(&(0, 1)), None);
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let level = parse_level("room", "---\n####\n#@>#\n####\n").unwrap();
+        let map = TileMap::from_map_info(&level);
+        assert!(map.get(-1, 0).is_none());
+        assert!(!map.is_walkable(4, 0));
+        assert!(map.blocks_sight(0, 5));
+    }
+}